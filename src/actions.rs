@@ -1,32 +1,1090 @@
-use hyper::http::Method;
+use crate::backoff;
+use crate::prometheus::ACTION_RELOADS;
+use anyhow::{anyhow, Context, Result};
+use glob::Pattern;
+use hyper::http::{HeaderName, HeaderValue, Method};
 use hyper::Uri;
+use k8s_openapi::api::core::v1::ContainerStatus;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
 /// Generate the action `BTreeMap`
 ///
-/// Modify this function to add or remove sidecar definitions and their associated shutdown procedures.
-pub fn generate() -> BTreeMap<String, Action> {
+/// These are the built-in sidecar definitions and their associated shutdown procedures,
+/// each a chain of actions that are tried in order until one succeeds.
+/// They can be overridden or extended with an action file, see `load`.
+pub fn generate() -> BTreeMap<String, Vec<Action>> {
     BTreeMap::from([
         (
             "cloudsql-proxy".into(),
-            Action::Portforward(Method::POST, "/quitquitquit".parse::<Uri>().unwrap(), 9091),
+            vec![Action::Portforward(HttpRequest::new(
+                Method::POST,
+                "/quitquitquit".parse::<Uri>().unwrap(),
+                9091,
+            ))],
         ),
         (
             "vks-sidecar".into(),
-            Action::Exec("/bin/kill -s INT 1".split(' ').map(String::from).collect()),
+            vec![Action::Exec {
+                command: "/bin/kill -s INT 1".split(' ').map(String::from).collect(),
+                timeout: None,
+                retry: None,
+            }],
         ),
         (
             "istio-proxy".into(),
-            Action::Portforward(Method::POST, "/quitquitquit".parse::<Uri>().unwrap(), 15000),
+            vec![Action::Portforward(HttpRequest::new(
+                Method::POST,
+                "/quitquitquit".parse::<Uri>().unwrap(),
+                15000,
+            ))],
         ),
         (
             "linkerd-proxy".into(),
-            Action::Portforward(Method::POST, "/shutdown".parse::<Uri>().unwrap(), 4191),
+            vec![Action::Portforward(HttpRequest::new(
+                Method::POST,
+                "/shutdown".parse::<Uri>().unwrap(),
+                4191,
+            ))],
         ),
     ])
 }
 
-#[derive(Debug)]
+/// Load an `ActionTable` from an action file on top of the built-in definitions
+///
+/// The file is YAML (or JSON) with a top level `actions` map from container name to a chain of actions,
+/// and a `rules` list for containers matched by name pattern or image.
+/// Entries in the file replace built-in entries with the same container name.
+pub fn load(path: &Path) -> Result<ActionTable> {
+    let contents =
+        std::fs::read_to_string(path).with_context(|| format!("could not read action file {}", path.display()))?;
+    let table = parse(&contents).with_context(|| format!("invalid action file {}", path.display()))?;
+    Ok(with_defaults(table))
+}
+
+/// Poll an action file for changes and publish the new `ActionTable` to `sender`
+///
+/// If the changed file is invalid, the previous actions are kept until the file changes again.
+pub async fn watch(path: PathBuf, interval: Duration, sender: watch::Sender<Arc<ActionTable>>) {
+    let mut last_contents = std::fs::read_to_string(&path).ok();
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let contents = match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => contents,
+            Err(e) => {
+                warn!("could not read action file {}: {e}", path.display());
+                continue;
+            }
+        };
+        if last_contents.as_ref() == Some(&contents) {
+            continue;
+        }
+        match parse(&contents) {
+            Ok(table) => {
+                let table = with_defaults(table);
+                info!("reloaded {} from {}", table, path.display());
+                sender.send_replace(Arc::new(table));
+                ACTION_RELOADS.with_label_values(&["success"]).inc();
+            }
+            Err(e) => {
+                error!(
+                    "keeping previous actions, invalid action file {}: {e:#}",
+                    path.display()
+                );
+                ACTION_RELOADS.with_label_values(&["failure"]).inc();
+            }
+        }
+        last_contents = Some(contents);
+    }
+}
+
+/// The `ActionTable` to use without an action file
+pub fn defaults() -> ActionTable {
+    with_defaults(ActionTable::default())
+}
+
+/// Put the built-in actions underneath the actions from an action file
+fn with_defaults(mut table: ActionTable) -> ActionTable {
+    let mut actions = generate();
+    actions.append(&mut table.actions);
+    table.actions = actions;
+    table
+}
+
+/// Parse and validate the contents of an action file
+///
+/// Entries are deserialized one by one so that errors can point at the offending container or rule.
+fn parse(contents: &str) -> Result<ActionTable> {
+    let file: ActionFile = serde_yaml::from_str(contents)?;
+    parse_table(file.actions, file.rules)
+}
+
+/// Parse and validate the `actions` and `rules` of an `ActionTable`
+pub fn parse_table(actions: BTreeMap<String, serde_json::Value>, rules: Vec<serde_json::Value>) -> Result<ActionTable> {
+    let actions = actions
+        .into_iter()
+        .map(|(name, value)| {
+            let chain = serde_json::from_value::<Vec<Action>>(value)
+                .map_err(anyhow::Error::from)
+                .and_then(|chain| validate_chain(&chain).map(|_| chain))
+                .with_context(|| format!("invalid actions for container `{name}`"))?;
+            Ok((name, chain))
+        })
+        .collect::<Result<_>>()?;
+    let rules = rules
+        .into_iter()
+        .enumerate()
+        .map(|(i, value)| {
+            serde_json::from_value::<Rule>(value)
+                .map_err(anyhow::Error::from)
+                .and_then(|rule| validate_chain(&rule.actions).map(|_| rule))
+                .with_context(|| format!("invalid rule #{}", i + 1))
+        })
+        .collect::<Result<_>>()?;
+    Ok(ActionTable { actions, rules })
+}
+
+/// The on-disk format of an action file, before the individual actions are parsed
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ActionFile {
+    #[serde(default)]
+    actions: BTreeMap<String, serde_json::Value>,
+    #[serde(default)]
+    rules: Vec<serde_json::Value>,
+}
+
+/// A set of action chains for sidecars, found by exact container name or by matching rules
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+pub struct ActionTable {
+    /// Shutdown actions, keyed by sidecar container name, tried in order until one succeeds
+    #[serde(default)]
+    #[schemars(schema_with = "crate::policy::structural_schema::<BTreeMap<String, Vec<Action>>>")]
+    pub actions: BTreeMap<String, Vec<Action>>,
+    /// Shutdown actions for sidecars that don't have an entry in `actions`, the first matching rule wins
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schemars(schema_with = "crate::policy::structural_schema::<Vec<Rule>>")]
+    pub rules: Vec<Rule>,
+}
+
+impl ActionTable {
+    /// Find the action chain for a sidecar, by its exact name first and then by the rules in order
+    pub fn find(&self, sidecar: &ContainerStatus) -> Option<&[Action]> {
+        self.actions.get(&sidecar.name).map(Vec::as_slice).or_else(|| {
+            self.rules
+                .iter()
+                .find(|rule| rule.matcher.matches(sidecar))
+                .map(|rule| rule.actions.as_slice())
+        })
+    }
+}
+
+impl fmt::Display for ActionTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "actions for {} sidecars and {} rules",
+            self.actions.len(),
+            self.rules.len()
+        )
+    }
+}
+
+/// An `ActionTable` whose actions and rules haven't been parsed yet
+///
+/// Policies are read like this, so that one that HAHAHA can't make sense of doesn't keep the others from being read.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+pub struct UncheckedActionTable {
+    /// Shutdown actions, keyed by sidecar container name, tried in order until one succeeds
+    #[serde(default)]
+    #[schemars(schema_with = "crate::policy::structural_schema::<BTreeMap<String, Vec<Action>>>")]
+    pub actions: BTreeMap<String, serde_json::Value>,
+    /// Shutdown actions for sidecars that don't have an entry in `actions`, the first matching rule wins
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schemars(schema_with = "crate::policy::structural_schema::<Vec<Rule>>")]
+    pub rules: Vec<serde_json::Value>,
+}
+
+impl UncheckedActionTable {
+    /// Parse and validate the actions and rules
+    pub fn parse(&self) -> Result<ActionTable> {
+        parse_table(self.actions.clone(), self.rules.clone())
+    }
+}
+
+/// An action chain for the sidecars that match a pattern
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    #[serde(rename = "match")]
+    pub matcher: Matcher,
+    /// Shutdown actions, tried in order until one succeeds
+    #[schemars(length(min = 1))]
+    pub actions: Vec<Action>,
+}
+
+/// How a `Rule` matches sidecars
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub enum Matcher {
+    /// Glob matching the container name, e.g. `cloudsql-proxy*`
+    Name(
+        #[serde(with = "as_string")]
+        #[schemars(with = "String")]
+        Pattern,
+    ),
+    /// Regular expression searched for in the container name, e.g. `^istio-(proxy|sidecar)$`
+    NameRegex(
+        #[serde(with = "as_string")]
+        #[schemars(with = "String")]
+        Regex,
+    ),
+    /// Glob matching the container image, e.g. `gcr.io/cloud-sql-connectors/*`
+    Image(
+        #[serde(with = "as_string")]
+        #[schemars(with = "String")]
+        Pattern,
+    ),
+}
+
+impl Matcher {
+    fn matches(&self, sidecar: &ContainerStatus) -> bool {
+        match self {
+            Matcher::Name(pattern) => pattern.matches(&sidecar.name),
+            Matcher::NameRegex(regex) => regex.is_match(&sidecar.name),
+            Matcher::Image(pattern) => pattern.matches(&sidecar.image),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
 pub enum Action {
-    Portforward(Method, Uri, u16),
-    Exec(Vec<String>),
+    /// Send an HTTP request to a port in the sidecar through a port-forward
+    Portforward(HttpRequest),
+    /// Run a command in the sidecar
+    Exec {
+        #[schemars(length(min = 1))]
+        command: Vec<String>,
+        /// How long the action may take, e.g. `5s`, see `Action::timeout` for the defaults
+        #[serde(default, with = "as_string_opt", skip_serializing_if = "Option::is_none")]
+        #[schemars(with = "Option<crate::policy::DurationString>")]
+        timeout: Option<humantime::Duration>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry: Option<Retry>,
+    },
+    /// Write a payload to a TCP port in the sidecar through a port-forward
+    Tcp {
+        #[schemars(range(min = 1, max = 65535))]
+        port: u16,
+        /// What to write, e.g. `"shutdown\n"`
+        payload: String,
+        /// Regular expression the response must match, the response isn't read if not set
+        #[serde(default, with = "as_string_opt", skip_serializing_if = "Option::is_none")]
+        #[schemars(with = "Option<String>")]
+        expect: Option<Regex>,
+        /// How long the action may take, e.g. `5s`, see `Action::timeout` for the defaults
+        #[serde(default, with = "as_string_opt", skip_serializing_if = "Option::is_none")]
+        #[schemars(with = "Option<crate::policy::DurationString>")]
+        timeout: Option<humantime::Duration>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry: Option<Retry>,
+    },
+    /// Call a gRPC method in the sidecar with an empty request through a port-forward
+    Grpc {
+        #[schemars(range(min = 1, max = 65535))]
+        port: u16,
+        /// Full name of the method, e.g. `/my.package.Admin/Shutdown`
+        method: String,
+        /// How long the action may take, e.g. `5s`, see `Action::timeout` for the defaults
+        #[serde(default, with = "as_string_opt", skip_serializing_if = "Option::is_none")]
+        #[schemars(with = "Option<crate::policy::DurationString>")]
+        timeout: Option<humantime::Duration>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry: Option<Retry>,
+    },
+    /// Send a signal to the main process of the sidecar from an ephemeral container
+    ///
+    /// For sidecars whose image has neither a shell nor an HTTP endpoint to shut it down.
+    Signal {
+        /// Name or number of the signal, e.g. `TERM`
+        signal: String,
+        /// Image of the ephemeral container, which must contain `kill`
+        #[serde(default = "default_signal_image")]
+        image: String,
+        /// How long the action may take, e.g. `5s`, see `Action::timeout` for the defaults
+        #[serde(default, with = "as_string_opt", skip_serializing_if = "Option::is_none")]
+        #[schemars(with = "Option<crate::policy::DurationString>")]
+        timeout: Option<humantime::Duration>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry: Option<Retry>,
+    },
+}
+
+/// How to retry an action that fails
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Retry {
+    /// How many times to retry before moving on to the next action
+    #[serde(default)]
+    pub attempts: u32,
+    /// Delay before the first retry, e.g. `500ms`, which doubles for every retry after that and is jittered
+    #[serde(default, with = "as_string_opt", skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<crate::policy::DurationString>")]
+    pub backoff: Option<humantime::Duration>,
+}
+
+impl Retry {
+    /// Delay before the first retry unless another one is given
+    pub const DEFAULT_BACKOFF: Duration = Duration::from_millis(500);
+    /// Longest delay between retries
+    pub const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+    /// How long to wait before retry number `attempt`, counting from 0
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let base = self.backoff.map_or(Self::DEFAULT_BACKOFF, Into::into);
+        backoff::jittered(base, Self::MAX_BACKOFF, attempt)
+    }
+}
+
+/// An HTTP request to a port in a sidecar
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct HttpRequest {
+    #[serde(with = "as_string")]
+    #[schemars(with = "String", regex(pattern = r"^[!#$%&'*+.^_`|~0-9A-Za-z-]+$"))]
+    pub method: Method,
+    #[serde(with = "as_string")]
+    #[schemars(with = "String", regex(pattern = r"^/"))]
+    pub path: Uri,
+    #[schemars(range(min = 1, max = 65535))]
+    pub port: u16,
+    /// How to reach the port, defaults to the transport configured for HAHAHA
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transport: Option<Transport>,
+    /// Extra request headers, which replace the default ones with the same name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    /// Request body, empty if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// Status codes that count as success, only 200 if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub statuses: Vec<u16>,
+    /// Use HTTPS instead of plain HTTP
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(schema_with = "crate::policy::tls_schema")]
+    pub tls: Option<Tls>,
+    /// How long the request may take, e.g. `5s`, 1 second if not set
+    #[serde(default, with = "as_string_opt", skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<crate::policy::DurationString>")]
+    pub timeout: Option<humantime::Duration>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<Retry>,
+}
+
+/// How to set up TLS for an HTTPS request
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Tls {
+    /// PEM file with the CA certificates to trust, e.g. from a mounted secret, instead of the system ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_file: Option<PathBuf>,
+    /// Server name to send and verify, instead of the address that is connected to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_name: Option<String>,
+    /// Accept any certificate, only meant for endpoints that are reached within the pod
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub insecure_skip_verify: bool,
+}
+
+impl HttpRequest {
+    /// A request without body or extra headers that only accepts 200
+    pub fn new(method: Method, path: Uri, port: u16) -> Self {
+        Self {
+            method,
+            path,
+            port,
+            transport: None,
+            headers: BTreeMap::new(),
+            body: None,
+            statuses: Vec::new(),
+            tls: None,
+            timeout: None,
+            retry: None,
+        }
+    }
+
+    /// How long the request may take
+    pub fn timeout(&self) -> Duration {
+        self.timeout.map_or(NETWORK_TIMEOUT, Into::into)
+    }
+
+    /// Whether a response with `status` counts as success
+    pub fn accepts(&self, status: u16) -> bool {
+        if self.statuses.is_empty() {
+            status == 200
+        } else {
+            self.statuses.contains(&status)
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        let Self {
+            path,
+            port,
+            headers,
+            statuses,
+            tls,
+            ..
+        } = self;
+        if *port == 0 {
+            return Err(anyhow!("port must be between 1 and 65535"));
+        }
+        if path.scheme().is_some() || path.authority().is_some() || !path.path().starts_with('/') {
+            return Err(anyhow!("path `{path}` must be an absolute path without scheme or host"));
+        }
+        for (name, value) in headers {
+            HeaderName::from_str(name).with_context(|| format!("invalid header name `{name}`"))?;
+            HeaderValue::from_str(value).with_context(|| format!("invalid value for header `{name}`"))?;
+        }
+        if let Some(status) = statuses.iter().find(|s| !(100..600).contains(*s)) {
+            return Err(anyhow!("status code {status} must be between 100 and 599"));
+        }
+        if let Some(tls) = tls {
+            if tls.insecure_skip_verify && tls.ca_file.is_some() {
+                return Err(anyhow!("caFile can't be used with insecureSkipVerify"));
+            }
+            if let Some(name) = &tls.server_name {
+                rustls::ServerName::try_from(name.as_str()).with_context(|| format!("invalid server name `{name}`"))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How an HTTP request reaches a port in a sidecar
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum Transport {
+    /// Through a port-forward via the API server
+    #[default]
+    Portforward,
+    /// Directly to the pod's IP address
+    PodIp,
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Portforward => f.write_str("portforward"),
+            Transport::PodIp => f.write_str("podIp"),
+        }
+    }
+}
+
+impl FromStr for Transport {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "portforward" => Ok(Transport::Portforward),
+            "podIp" => Ok(Transport::PodIp),
+            _ => Err(anyhow!("unknown transport `{s}`, expected `portforward` or `podIp`")),
+        }
+    }
+}
+
+/// How long network actions may take unless another timeout is given
+const NETWORK_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long exec actions may take unless another timeout is given
+const EXEC_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long signal actions may take unless another timeout is given, which includes pulling the image
+const SIGNAL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Image used for `Action::Signal` unless another one is given
+pub const DEFAULT_SIGNAL_IMAGE: &str = "busybox:1.36";
+
+fn default_signal_image() -> String {
+    DEFAULT_SIGNAL_IMAGE.into()
+}
+
+/// Check that a chain has at least one action, and that all of them are valid
+pub fn validate_chain(chain: &[Action]) -> Result<()> {
+    if chain.is_empty() {
+        return Err(anyhow!("at least one action is required"));
+    }
+    for (i, action) in chain.iter().enumerate() {
+        action.validate().with_context(|| format!("action #{}", i + 1))?;
+    }
+    Ok(())
+}
+
+impl Action {
+    /// Use `default` as the transport of an HTTP action that doesn't have one
+    pub fn or_transport(mut self, default: Transport) -> Self {
+        if let Action::Portforward(request) = &mut self {
+            request.transport.get_or_insert(default);
+        }
+        self
+    }
+
+    /// How long the action may take
+    pub fn timeout(&self) -> Duration {
+        match self {
+            Action::Portforward(request) => request.timeout(),
+            Action::Exec { timeout, .. } => timeout.map_or(EXEC_TIMEOUT, Into::into),
+            Action::Tcp { timeout, .. } | Action::Grpc { timeout, .. } => timeout.map_or(NETWORK_TIMEOUT, Into::into),
+            Action::Signal { timeout, .. } => timeout.map_or(SIGNAL_TIMEOUT, Into::into),
+        }
+    }
+
+    /// How to retry the action if it fails, which is not at all by default
+    pub fn retry(&self) -> Retry {
+        let retry = match self {
+            Action::Portforward(request) => &request.retry,
+            Action::Exec { retry, .. }
+            | Action::Tcp { retry, .. }
+            | Action::Grpc { retry, .. }
+            | Action::Signal { retry, .. } => retry,
+        };
+        retry.clone().unwrap_or_default()
+    }
+
+    /// Short name of the kind of action, e.g. for metric labels
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Action::Portforward(_) => "portforward",
+            Action::Exec { .. } => "exec",
+            Action::Tcp { .. } => "tcp",
+            Action::Grpc { .. } => "grpc",
+            Action::Signal { .. } => "signal",
+        }
+    }
+
+    /// Check the parts of an `Action` that the type system can't
+    pub fn validate(&self) -> Result<()> {
+        if self.timeout().is_zero() {
+            return Err(anyhow!("timeout must be longer than 0"));
+        }
+        match self {
+            Action::Portforward(request) => request.validate()?,
+            Action::Exec { command, .. } => {
+                if command.first().is_none_or(String::is_empty) {
+                    return Err(anyhow!("command must not be empty"));
+                }
+            }
+            Action::Tcp { port, payload, .. } => {
+                if *port == 0 {
+                    return Err(anyhow!("port must be between 1 and 65535"));
+                }
+                if payload.is_empty() {
+                    return Err(anyhow!("payload must not be empty"));
+                }
+            }
+            Action::Grpc { port, method, .. } => {
+                if *port == 0 {
+                    return Err(anyhow!("port must be between 1 and 65535"));
+                }
+                let valid =
+                    method
+                        .strip_prefix('/')
+                        .and_then(|m| m.split_once('/'))
+                        .is_some_and(|(service, method)| {
+                            !service.is_empty() && !method.is_empty() && !method.contains('/')
+                        });
+                if !valid {
+                    return Err(anyhow!(
+                        "method `{method}` must look like `/<package>.<service>/<method>`"
+                    ));
+                }
+            }
+            Action::Signal { signal, image, .. } => {
+                if signal.is_empty() || !signal.chars().all(|c| c.is_ascii_alphanumeric()) {
+                    return Err(anyhow!("signal `{signal}` must be a signal name or number"));
+                }
+                if image.is_empty() {
+                    return Err(anyhow!("image must not be empty"));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Portforward(request) => {
+                let HttpRequest { method, path, port, .. } = request;
+                write!(f, "portforward ({method} {path} at port {port}")?;
+                if request.tls.is_some() {
+                    f.write_str(" with TLS")?;
+                }
+                if request.transport == Some(Transport::PodIp) {
+                    f.write_str(" via pod IP")?;
+                }
+                f.write_str(")")
+            }
+            Action::Exec { command, .. } => write!(f, "exec (`{}`)", command.join(" ")),
+            Action::Tcp { port, payload, .. } => write!(f, "tcp ({payload:?} at port {port})"),
+            Action::Grpc { port, method, .. } => write!(f, "grpc ({method} at port {port})"),
+            Action::Signal { signal, image, .. } => write!(f, "signal ({signal} from {image})"),
+        }
+    }
+}
+
+/// Parse the short form of an `Action` used in pod annotations
+///
+/// `portforward:<method>:<path>:<port>`, e.g. `portforward:POST:/quitquitquit:8080`, or
+/// `exec:<command>`, e.g. `exec:/bin/kill -s TERM 1`, or
+/// `tcp:<port>:<payload>`, e.g. `tcp:9000:shutdown`, or
+/// `grpc:<port>:<method>`, e.g. `grpc:9000:/my.package.Admin/Shutdown`, or
+/// `signal:<signal>[:<image>]`, e.g. `signal:TERM` or `signal:TERM:busybox:1.36`.
+impl FromStr for Action {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let action = match s.split_once(':') {
+            Some(("portforward", rest)) => {
+                let (method, rest) = rest
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("expected `portforward:<method>:<path>:<port>`, got `{s}`"))?;
+                let (path, port) = rest
+                    .rsplit_once(':')
+                    .ok_or_else(|| anyhow!("expected `portforward:<method>:<path>:<port>`, got `{s}`"))?;
+                Action::Portforward(HttpRequest::new(
+                    method.parse().with_context(|| format!("invalid method `{method}`"))?,
+                    path.parse().with_context(|| format!("invalid path `{path}`"))?,
+                    port.parse().with_context(|| format!("invalid port `{port}`"))?,
+                ))
+            }
+            Some(("exec", command)) => Action::Exec {
+                command: command.split_whitespace().map(String::from).collect(),
+                timeout: None,
+                retry: None,
+            },
+            Some(("tcp", rest)) => {
+                let (port, payload) = rest
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("expected `tcp:<port>:<payload>`, got `{s}`"))?;
+                Action::Tcp {
+                    port: port.parse().with_context(|| format!("invalid port `{port}`"))?,
+                    payload: payload.into(),
+                    expect: None,
+                    timeout: None,
+                    retry: None,
+                }
+            }
+            Some(("grpc", rest)) => {
+                let (port, method) = rest
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("expected `grpc:<port>:<method>`, got `{s}`"))?;
+                Action::Grpc {
+                    port: port.parse().with_context(|| format!("invalid port `{port}`"))?,
+                    method: method.into(),
+                    timeout: None,
+                    retry: None,
+                }
+            }
+            Some(("signal", rest)) => {
+                let (signal, image) = rest.split_once(':').unwrap_or((rest, DEFAULT_SIGNAL_IMAGE));
+                Action::Signal {
+                    signal: signal.into(),
+                    image: image.into(),
+                    timeout: None,
+                    retry: None,
+                }
+            }
+            _ => {
+                return Err(anyhow!(
+                    "expected `portforward:...`, `exec:...`, `tcp:...`, `grpc:...` or `signal:...`, got `{s}`"
+                ))
+            }
+        };
+        action.validate()?;
+        Ok(action)
+    }
+}
+
+/// Like `as_string`, for optional values
+mod as_string_opt {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+    use std::fmt::Display;
+    use std::str::FromStr;
+
+    pub fn serialize<S, T>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        T: Display,
+    {
+        match value {
+            Some(value) => serializer.collect_str(value),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: FromStr,
+        T::Err: Display,
+    {
+        Option::<String>::deserialize(deserializer)?
+            .map(|s| s.parse().map_err(de::Error::custom))
+            .transpose()
+    }
+}
+
+/// (De)serialize anything that can be displayed as and parsed from a string, e.g. `Method` and `Uri`
+mod as_string {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+    use std::fmt::Display;
+    use std::str::FromStr;
+
+    pub fn serialize<S, T>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        T: Display,
+    {
+        serializer.collect_str(value)
+    }
+
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: FromStr,
+        T::Err: Display,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_entries_override_and_extend_defaults() {
+        let table = with_defaults(
+            parse(
+                r#"
+actions:
+  istio-proxy:
+    - exec:
+        command: ["/bin/kill", "-s", "TERM", "1"]
+  my-sidecar:
+    - portforward:
+        method: PUT
+        path: /shutdown
+        port: 8080
+        transport: podIp
+        headers:
+          Authorization: Bearer secret
+        body: '{"graceful": true}'
+        statuses: [200, 202, 204]
+    - exec:
+        command: ["/bin/kill", "-s", "KILL", "1"]
+"#,
+            )
+            .unwrap(),
+        );
+        let actions = table.actions;
+
+        assert_eq!(actions.len(), 5);
+        assert!(matches!(actions["istio-proxy"][..], [Action::Exec { .. }]));
+        let [Action::Portforward(request), Action::Exec { .. }] = &actions["my-sidecar"][..] else {
+            panic!("expected portforward and exec actions");
+        };
+        assert_eq!(request.method, Method::PUT);
+        assert_eq!(request.path, "/shutdown");
+        assert_eq!(request.port, 8080);
+        assert_eq!(request.transport, Some(Transport::PodIp));
+        assert_eq!(request.headers["Authorization"], "Bearer secret");
+        assert_eq!(request.body.as_deref(), Some(r#"{"graceful": true}"#));
+        assert!(request.accepts(204));
+        assert!(!request.accepts(201));
+    }
+
+    #[test]
+    fn default_transport_only_applies_to_actions_without_one() {
+        let transport = |action: Action| match action.or_transport(Transport::PodIp) {
+            Action::Portforward(request) => request.transport,
+            _ => panic!("expected portforward action"),
+        };
+        let Action::Portforward(mut request) = "portforward:POST:/quit:8080".parse().unwrap() else {
+            panic!("expected portforward action");
+        };
+        assert_eq!(transport(Action::Portforward(request.clone())), Some(Transport::PodIp));
+        request.transport = Some(Transport::Portforward);
+        assert_eq!(transport(Action::Portforward(request)), Some(Transport::Portforward));
+    }
+
+    #[test]
+    fn timeouts_and_retries_are_parsed() {
+        let table = parse(
+            r#"
+actions:
+  a:
+    - portforward: {method: POST, path: /quit, port: 8080, timeout: 2s, retry: {attempts: 3}}
+    - exec: {command: [/bin/quit], timeout: 1m, retry: {attempts: 2, backoff: 1s}}
+    - signal: {signal: TERM}
+"#,
+        )
+        .unwrap();
+        let actions = &table.actions["a"];
+        let timeouts: Vec<_> = actions.iter().map(Action::timeout).collect();
+        assert_eq!(
+            timeouts,
+            [Duration::from_secs(2), Duration::from_secs(60), SIGNAL_TIMEOUT]
+        );
+        let attempts: Vec<_> = actions.iter().map(|a| a.retry().attempts).collect();
+        assert_eq!(attempts, [3, 2, 0]);
+        let delay = actions[1].retry().delay(1);
+        assert!(
+            delay <= Duration::from_secs(2) && delay >= Duration::from_secs(1),
+            "{:?}",
+            delay
+        );
+    }
+
+    #[test]
+    fn json_is_accepted() {
+        let table = parse(r#"{"actions": {"my-sidecar": [{"exec": {"command": ["/bin/true"]}}]}}"#).unwrap();
+        assert!(matches!(table.actions["my-sidecar"][..], [Action::Exec { .. }]));
+    }
+
+    #[test]
+    fn malformed_entries_are_rejected() {
+        let cases = [
+            (
+                "actions: {a: [{portforward: {method: POST, path: /quit, port: 0}}]}",
+                "port must be",
+            ),
+            (
+                "actions: {a: [{portforward: {method: POST, path: 'http://x/quit', port: 80}}]}",
+                "must be an absolute path",
+            ),
+            (
+                "actions: {a: [{portforward: {method: POST, port: 80}}]}",
+                "missing field `path`",
+            ),
+            (
+                "actions: {a: [{portforward: {method: POST, path: /quit, port: 80, headers: {'a b': c}}}]}",
+                "invalid header name `a b`",
+            ),
+            (
+                "actions: {a: [{portforward: {method: POST, path: /quit, port: 80, statuses: [42]}}]}",
+                "status code 42 must be between 100 and 599",
+            ),
+            (
+                "actions: {a: [{portforward: {method: POST, path: /quit, port: 80, tls: {caFile: /ca.pem, insecureSkipVerify: true}}}]}",
+                "caFile can't be used with insecureSkipVerify",
+            ),
+            (
+                "actions: {a: [{tcp: {port: 9000, payload: quit, expect: '('}}]}",
+                "regex parse error",
+            ),
+            (
+                "actions: {a: [{exec: {command: [/bin/true]}}, {exec: {command: []}}]}",
+                "action #2: command must not be empty",
+            ),
+            (
+                "actions: {a: [{exec: {command: [/bin/true], timeout: 0s}}]}",
+                "timeout must be longer than 0",
+            ),
+            (
+                "actions: {a: [{grpc: {port: 9000, method: /a.B/Quit, timeout: soon}}]}",
+                "expected number",
+            ),
+            (
+                "actions: {a: [{tcp: {port: 9000, payload: quit, retry: {times: 2}}}]}",
+                "unknown field `times`",
+            ),
+            ("actions: {a: []}", "at least one action is required"),
+            ("actions: {a: {exec: {command: [/bin/true]}}}", "expected a sequence"),
+            ("actions: {a: [{kill: {}}]}", "unknown variant `kill`"),
+            ("actions: {a: [{signal: {}}]}", "missing field `signal`"),
+            ("action: {}", "unknown field `action`"),
+            (
+                "rules: [{match: {name: '[a-'}, actions: [{exec: {command: [/bin/true]}}]}]",
+                "invalid rule #1",
+            ),
+            (
+                "rules: [{match: {nameRegex: '('}, actions: [{exec: {command: [/bin/true]}}]}]",
+                "regex parse error",
+            ),
+            (
+                "rules: [{match: {image: '*'}, actions: [{exec: {command: []}}]}]",
+                "command must not be empty",
+            ),
+        ];
+        for (contents, expected) in cases {
+            let err = format!("{:#}", parse(contents).unwrap_err());
+            assert!(err.contains(expected), "expected `{}` in `{}`", expected, err);
+        }
+    }
+
+    #[test]
+    fn rules_match_in_order_after_exact_names() {
+        let table = with_defaults(
+            parse(
+                r#"
+rules:
+  - match: {image: "gcr.io/cloud-sql-connectors/*"}
+    actions: [{exec: {command: [image]}}]
+  - match: {name: "cloudsql-proxy*"}
+    actions: [{exec: {command: [glob]}}]
+  - match: {nameRegex: "^istio-"}
+    actions: [{exec: {command: [regex]}}]
+"#,
+            )
+            .unwrap(),
+        );
+        let command = |name: &str, image: &str| {
+            let sidecar = ContainerStatus {
+                name: name.into(),
+                image: image.into(),
+                ..Default::default()
+            };
+            match table.find(&sidecar) {
+                Some([Action::Exec { command, .. }]) => Some(command[0].clone()),
+                Some([Action::Portforward(_)]) => Some("exact".into()),
+                _ => None,
+            }
+        };
+
+        assert_eq!(
+            command("cloudsql-proxy", "gcr.io/cloud-sql-connectors/cloud-sql-proxy:2"),
+            Some("exact".into())
+        );
+        assert_eq!(
+            command("cloudsql-proxy-2", "gcr.io/cloud-sql-connectors/cloud-sql-proxy:2"),
+            Some("image".into())
+        );
+        assert_eq!(
+            command("cloudsql-proxy-2", "gcr.io/cloudsql-docker/gce-proxy:1"),
+            Some("glob".into())
+        );
+        assert_eq!(
+            command("istio-sidecar", "docker.io/istio/proxyv2"),
+            Some("regex".into())
+        );
+        assert_eq!(command("my-istio-sidecar", "docker.io/istio/proxyv2"), None);
+    }
+
+    #[test]
+    fn short_form_is_parsed() {
+        let Action::Portforward(request) = "portforward:POST:/quit:8080".parse().unwrap() else {
+            panic!("expected portforward action");
+        };
+        assert_eq!(request.method, Method::POST);
+        assert_eq!(request.path, "/quit");
+        assert_eq!(request.port, 8080);
+        assert!(request.accepts(200));
+        assert!(!request.accepts(204));
+
+        let Action::Exec { command, .. } = "exec:/bin/kill  -TERM 1".parse().unwrap() else {
+            panic!("expected exec action");
+        };
+        assert_eq!(command, ["/bin/kill", "-TERM", "1"]);
+
+        let Action::Tcp {
+            port, payload, expect, ..
+        } = "tcp:9000:quit now".parse().unwrap()
+        else {
+            panic!("expected tcp action");
+        };
+        assert_eq!(port, 9000);
+        assert_eq!(payload, "quit now");
+        assert!(expect.is_none());
+
+        let Action::Grpc { port, method, .. } = "grpc:9000:/my.package.Admin/Shutdown".parse().unwrap() else {
+            panic!("expected grpc action");
+        };
+        assert_eq!(port, 9000);
+        assert_eq!(method, "/my.package.Admin/Shutdown");
+
+        let Action::Signal { signal, image, .. } = "signal:TERM".parse().unwrap() else {
+            panic!("expected signal action");
+        };
+        assert_eq!(signal, "TERM");
+        assert_eq!(image, DEFAULT_SIGNAL_IMAGE);
+
+        let Action::Signal { signal, image, .. } = "signal:9:registry.example.com/tools:1.0".parse().unwrap() else {
+            panic!("expected signal action");
+        };
+        assert_eq!(signal, "9");
+        assert_eq!(image, "registry.example.com/tools:1.0");
+    }
+
+    #[test]
+    fn malformed_short_forms_are_rejected() {
+        let cases = [
+            (
+                "portforward:POST:/quit",
+                "expected `portforward:<method>:<path>:<port>`",
+            ),
+            ("portforward:POST:/quit:http", "invalid port `http`"),
+            ("portforward:POST", "expected `portforward:<method>:<path>:<port>`"),
+            ("portforward:POST:/quit:99999", "invalid port `99999`"),
+            ("portforward:POST:quit:80", "must be an absolute path"),
+            ("exec:", "command must not be empty"),
+            ("signal:", "must be a signal name or number"),
+            ("signal:-9", "must be a signal name or number"),
+            ("signal:TERM:", "image must not be empty"),
+            ("tcp:9000", "expected `tcp:<port>:<payload>`"),
+            ("tcp:9000:", "payload must not be empty"),
+            ("grpc:9000:Shutdown", "must look like `/<package>.<service>/<method>`"),
+            ("grpc:9000:/a.Admin/", "must look like `/<package>.<service>/<method>`"),
+            (
+                "kill:1",
+                "expected `portforward:...`, `exec:...`, `tcp:...`, `grpc:...` or `signal:...`",
+            ),
+        ];
+        for (s, expected) in cases {
+            let err = format!("{:#}", s.parse::<Action>().unwrap_err());
+            assert!(err.contains(expected), "expected `{}` in `{}`", expected, err);
+        }
+    }
+
+    #[tokio::test]
+    async fn watch_reloads_valid_files_and_keeps_previous_actions_on_invalid_ones() {
+        let path = std::env::temp_dir().join(format!("hahaha-actions-{}.yaml", std::process::id()));
+        std::fs::write(&path, "actions: {}").unwrap();
+        let (sender, mut receiver) = watch::channel(Arc::new(load(&path).unwrap()));
+        let watcher = tokio::spawn(watch(path.clone(), Duration::from_millis(10), sender));
+        let timeout = Duration::from_secs(5);
+        // give the watcher a chance to read the initial file
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        std::fs::write(&path, "actions: {my-sidecar: [{exec: {command: [/bin/true]}}]}").unwrap();
+        tokio::time::timeout(timeout, receiver.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(receiver.borrow_and_update().actions.contains_key("my-sidecar"));
+
+        std::fs::write(&path, "actions: {my-sidecar: [{exec: {command: []}}]}").unwrap();
+        std::fs::write(&path, "actions: {other-sidecar: [{exec: {command: []}}]}").unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(100), receiver.changed())
+            .await
+            .is_err());
+        assert!(receiver.borrow().actions.contains_key("my-sidecar"));
+
+        watcher.abort();
+        std::fs::remove_file(&path).unwrap();
+    }
 }
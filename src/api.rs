@@ -1,38 +1,214 @@
 #[cfg(test)]
 use mockall::automock;
 
-use crate::actions::Action;
+use crate::actions::{Action, HttpRequest, Transport};
+use crate::tls;
 use anyhow::anyhow;
 use async_trait::async_trait;
-use hyper::http::Method;
-use hyper::{body, Body, Request, Uri};
+use hyper::body::HttpBody;
+use hyper::http::{HeaderMap, HeaderName, HeaderValue, Method};
+use hyper::{body, Body, Request};
 use k8s_openapi::api::core::v1::Pod;
-use kube::api::{Api, AttachParams};
-use std::time::Duration;
-use tracing::{debug, error, info};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Status;
+use kube::api::{Api, AttachParams, DeleteParams, EvictParams, Patch, PatchParams};
+use rand::Rng;
+use regex::Regex;
+use serde_json::json;
+use std::{fmt, str::FromStr, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use tracing::{debug, error, info, warn};
+
+static SIGNAL_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 /// Shutdown method for Apis with type Pod
 #[cfg_attr(test, automock)]
 #[async_trait]
 pub trait Destroyer {
-    /// Shuts down a container in a given pod with a given Action
+    /// Shuts down a container in a given pod with the first Action in a chain that succeeds
+    ///
+    /// `retried` is how many times the first Action has been retried before.
+    /// An Action that fails with retries left ends the chain with a `RetryLater` error, instead of waiting here.
     ///
     /// This is the primary public facing business function for this application
-    async fn shutdown(&self, action: &Action, pod_name: &str, container_name: &str) -> anyhow::Result<()>;
+    async fn shutdown(
+        &self,
+        actions: &[Action],
+        retried: u32,
+        pod_name: &str,
+        container_name: &str,
+    ) -> anyhow::Result<Shutdown>;
+    /// Gets rid of a whole pod whose sidecars won't shut down
+    async fn remove(&self, pod_name: &str, how: LastResort) -> anyhow::Result<()>;
+}
+
+/// How a sidecar was shut down
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Shutdown {
+    /// Index of the Action in the chain that succeeded
+    pub action: usize,
+    /// Truncated output of the Action, if it printed anything
+    pub output: Option<String>,
+}
+
+/// An Action failed and should be retried after `delay`, which is left to the caller
+#[derive(Debug, thiserror::Error)]
+#[error("{error}")]
+pub struct RetryLater {
+    /// Index of the Action in the chain to retry
+    pub action: usize,
+    /// How many times the Action will have been retried
+    pub retried: u32,
+    /// How long to wait before retrying
+    pub delay: Duration,
+    pub error: anyhow::Error,
+}
+
+/// What to do with a pod whose sidecars can't be shut down
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LastResort {
+    Delete,
+    Evict,
+}
+
+impl fmt::Display for LastResort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LastResort::Delete => f.write_str("delete"),
+            LastResort::Evict => f.write_str("evict"),
+        }
+    }
+}
+
+impl FromStr for LastResort {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "delete" => Ok(LastResort::Delete),
+            "evict" => Ok(LastResort::Evict),
+            _ => Err(anyhow!("unknown last resort `{s}`, expected `delete` or `evict`")),
+        }
+    }
 }
 
 #[async_trait]
 impl Destroyer for Api<Pod> {
-    async fn shutdown(&self, action: &Action, pod_name: &str, container_name: &str) -> anyhow::Result<()> {
-        shutdown_pod(self, action, pod_name, container_name).await
+    async fn shutdown(
+        &self,
+        actions: &[Action],
+        retried: u32,
+        pod_name: &str,
+        container_name: &str,
+    ) -> anyhow::Result<Shutdown> {
+        shutdown_pod(self, actions, retried, pod_name, container_name).await
+    }
+
+    async fn remove(&self, pod_name: &str, how: LastResort) -> anyhow::Result<()> {
+        match how {
+            LastResort::Delete => {
+                self.delete(pod_name, &DeleteParams::default()).await?;
+            }
+            LastResort::Evict => {
+                self.evict(pod_name, &EvictParams::default()).await?;
+            }
+        }
+        info!("{pod_name}: removed pod as a last resort ({how})");
+        Ok(())
     }
 }
 
-async fn shutdown_pod(pod: &Api<Pod>, action: &Action, pod_name: &str, container_name: &str) -> anyhow::Result<()> {
+async fn shutdown_pod(
+    pod: &Api<Pod>,
+    actions: &[Action],
+    retried: u32,
+    pod_name: &str,
+    container_name: &str,
+) -> anyhow::Result<Shutdown> {
+    let mut errors = Vec::new();
+    for (i, action) in actions.iter().enumerate() {
+        // only the first action can have been tried before
+        let attempt = if i == 0 { retried } else { 0 };
+        match shutdown_action(pod, action, pod_name, container_name).await {
+            Ok(output) => return Ok(Shutdown { action: i, output }),
+            Err(error) if attempt < action.retry().attempts => {
+                let delay = action.retry().delay(attempt);
+                warn!("{pod_name}: {action} failed for {container_name}, retrying in {delay:?}: {error}");
+                return Err(RetryLater {
+                    action: i,
+                    retried: attempt + 1,
+                    delay,
+                    error,
+                }
+                .into());
+            }
+            Err(err) => {
+                if i + 1 < actions.len() {
+                    warn!("{pod_name}: {action} failed for {container_name}, trying the next action: {err}");
+                }
+                errors.push(err);
+            }
+        }
+    }
+    if errors.len() == 1 {
+        return Err(errors.remove(0));
+    }
+    let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+    Err(anyhow!("all {} actions failed: {}", errors.len(), errors.join("; ")))
+}
+
+/// Runs a single Action within its timeout, returning its output if it has any
+///
+/// The timeout covers the whole attempt, including setting up port-forwards, connections and handshakes.
+async fn shutdown_action(
+    pod: &Api<Pod>,
+    action: &Action,
+    pod_name: &str,
+    container_name: &str,
+) -> anyhow::Result<Option<String>> {
+    match tokio::time::timeout(action.timeout(), run_action(pod, action, pod_name, container_name)).await {
+        Ok(res) => res,
+        Err(_) => Err(anyhow!(format!(
+            "{pod_name}: {action} failed for {container_name}: timed out after {:?}",
+            action.timeout()
+        ))),
+    }
+}
+
+async fn run_action(
+    pod: &Api<Pod>,
+    action: &Action,
+    pod_name: &str,
+    container_name: &str,
+) -> anyhow::Result<Option<String>> {
     match action {
-        Action::Exec(command) => shutdown_exec(pod, command, pod_name, container_name).await,
-        Action::Portforward(method, path, port) => {
-            shutdown_portforward(pod, method, path, *port, pod_name, container_name).await
+        Action::Exec { command, .. } => shutdown_exec(pod, command, pod_name, container_name).await,
+        Action::Portforward(request) => {
+            match request.transport.unwrap_or_default() {
+                Transport::Portforward => shutdown_portforward(pod, request, pod_name, container_name).await?,
+                Transport::PodIp => shutdown_pod_ip(pod, request, pod_name, container_name).await?,
+            }
+            Ok(None)
+        }
+        Action::Tcp {
+            port, payload, expect, ..
+        } => {
+            let stream = portforward_stream(pod, *port, pod_name).await?;
+            send_tcp(stream, payload, expect.as_ref(), *port, pod_name).await?;
+            info!("{pod_name}: sent {payload:?} at port {port} to {container_name}");
+            Ok(None)
+        }
+        Action::Grpc { port, method, .. } => {
+            let stream = portforward_stream(pod, *port, pod_name).await?;
+            send_grpc(stream, method, *port, pod_name).await?;
+            info!("{pod_name}: called {method} at port {port} in {container_name}");
+            Ok(None)
+        }
+        Action::Signal { signal, image, .. } => {
+            shutdown_signal(pod, signal, image, pod_name, container_name).await?;
+            Ok(None)
         }
     }
 }
@@ -42,71 +218,753 @@ async fn shutdown_exec(
     command: &Vec<String>,
     pod_name: &str,
     container_name: &str,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<Option<String>> {
     debug!("{pod_name}: running command: {command:?}");
-    match pod
-        .exec(
-            pod_name,
-            command,
-            &AttachParams::default().container(container_name).stdout(false),
-        )
+    let mut process = pod
+        .exec(pod_name, command, &AttachParams::default().container(container_name))
         .await
-    {
-        Ok(_) => info!("{pod_name}: sent `{command:?}` to {container_name}",),
-        Err(err) => return Err(anyhow!(format!("{pod_name}: exec failed in {container_name}: {err}"))),
+        .map_err(|err| anyhow!(format!("{pod_name}: exec failed in {container_name}: {err}")))?;
+    let status = process.take_status();
+    let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+    let (stdout_reader, stderr_reader) = (process.stdout(), process.stderr());
+    let read_stdout = async {
+        if let Some(mut reader) = stdout_reader {
+            reader.read_to_end(&mut stdout).await?;
+        }
+        Ok::<_, std::io::Error>(())
     };
-    Ok(())
+    let read_stderr = async {
+        if let Some(mut reader) = stderr_reader {
+            reader.read_to_end(&mut stderr).await?;
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    let (read_stdout, read_stderr) = tokio::join!(read_stdout, read_stderr);
+    // the stream is cut off when the command stops the container, so this says nothing about how it went
+    if let Err(err) = read_stdout.and(read_stderr) {
+        debug!("{pod_name}: output of `{command:?}` in {container_name} was cut off: {err}");
+    }
+    let status = match status {
+        Some(status) => status.await,
+        None => None,
+    };
+    if status.is_none() {
+        info!("{pod_name}: `{command:?}` in {container_name} ended without an exit status, leaving it to the termination check");
+    }
+    let output = exec_output(&stdout, &stderr);
+    if let Some(output) = &output {
+        debug!("{pod_name}: `{command:?}` in {container_name} printed {output}");
+    }
+    if let Err(err) = exec_status(status) {
+        return Err(match &output {
+            Some(output) => anyhow!("{pod_name}: `{command:?}` {err} in {container_name}: {output}"),
+            None => anyhow!("{pod_name}: `{command:?}` {err} in {container_name}"),
+        });
+    }
+    info!("{pod_name}: sent `{command:?}` to {container_name}",);
+    Ok(output)
+}
+
+/// Longest output of an exec'd command that is kept for Events and logs
+const MAX_EXEC_OUTPUT: usize = 256;
+
+/// What an exec'd command printed, trimmed and truncated to `MAX_EXEC_OUTPUT` characters per stream
+fn exec_output(stdout: &[u8], stderr: &[u8]) -> Option<String> {
+    let parts: Vec<String> = [("stdout", stdout), ("stderr", stderr)]
+        .iter()
+        .filter_map(|(name, output)| {
+            let output = String::from_utf8_lossy(output);
+            let output = output.trim();
+            if output.is_empty() {
+                return None;
+            }
+            match output.char_indices().nth(MAX_EXEC_OUTPUT) {
+                Some((end, _)) => Some(format!("{name}: {}…", &output[..end])),
+                None => Some(format!("{name}: {output}")),
+            }
+        })
+        .collect();
+    (!parts.is_empty()).then(|| parts.join(", "))
+}
+
+/// Checks the status an exec'd command ended with, which is only successful if the command exited with 0
+///
+/// A missing status is inconclusive rather than a failure: a command that kills the container's main process,
+/// e.g. `kill -s KILL 1`, ends the exec stream before any status is sent.
+/// Whether the sidecar actually stopped is then up to the check after the verify deadline.
+fn exec_status(status: Option<Status>) -> anyhow::Result<()> {
+    let Some(status) = status else {
+        return Ok(());
+    };
+    if status.status.as_deref() == Some("Success") {
+        return Ok(());
+    }
+    let exit_code = status
+        .details
+        .and_then(|details| details.causes)
+        .unwrap_or_default()
+        .into_iter()
+        .find(|cause| cause.reason.as_deref() == Some("ExitCode"))
+        .and_then(|cause| cause.message);
+    match (exit_code, status.message) {
+        (Some(code), _) => Err(anyhow!("exited with code {code}")),
+        (None, Some(message)) => Err(anyhow!("failed: {message}")),
+        (None, None) => Err(anyhow!("failed")),
+    }
+}
+
+/// Starts an ephemeral container targeting the sidecar, which sends a signal to the sidecar's main process
+///
+/// Ephemeral containers can't be removed from a pod, so every attempt adds a new one.
+async fn shutdown_signal(
+    pod: &Api<Pod>,
+    signal: &str,
+    image: &str,
+    pod_name: &str,
+    container_name: &str,
+) -> anyhow::Result<()> {
+    let existing: Vec<String> = pod
+        .get_ephemeral_containers(pod_name)
+        .await?
+        .spec
+        .and_then(|spec| spec.ephemeral_containers)
+        .into_iter()
+        .flatten()
+        .map(|container| container.name)
+        .collect();
+    let name = ephemeral_container_name(container_name, &existing);
+    let patch = json!({
+        "spec": {
+            "ephemeralContainers": [{
+                "name": name,
+                "image": image,
+                "command": ["kill", "-s", signal, "1"],
+                "targetContainerName": container_name,
+            }]
+        }
+    });
+    debug!("{pod_name}: starting ephemeral container {name} to send {signal} to {container_name}");
+    pod.patch_ephemeral_containers(pod_name, &PatchParams::default(), &Patch::Strategic(patch))
+        .await?;
+
+    // polled until the ephemeral container finishes, or the timeout of the action runs out
+    loop {
+        tokio::time::sleep(SIGNAL_POLL_INTERVAL).await;
+        let terminated = pod
+            .get(pod_name)
+            .await?
+            .status
+            .and_then(|status| status.ephemeral_container_statuses)
+            .and_then(|statuses| statuses.into_iter().find(|s| s.name == name))
+            .and_then(|status| status.state)
+            .and_then(|state| state.terminated);
+        match terminated {
+            Some(terminated) if terminated.exit_code == 0 => {
+                info!("{pod_name}: sent {signal} to {container_name} from ephemeral container {name}");
+                return Ok(());
+            }
+            Some(terminated) => {
+                return Err(anyhow!(
+                    "{pod_name}: sending {signal} to {container_name} failed: ephemeral container {name} exited with code {}",
+                    terminated.exit_code
+                ))
+            }
+            None => continue,
+        }
+    }
+}
+
+/// Characters for the random suffix of ephemeral container names, like Kubernetes uses for generated names
+const NAME_SUFFIX_CHARS: &[u8] = b"bcdfghjklmnpqrstvwxz2456789";
+
+/// Come up with a name for an ephemeral container that targets `container_name`, which isn't in `existing`
+///
+/// The name has a random suffix, so that attempts racing each other or other tools don't end up with the same name.
+fn ephemeral_container_name(container_name: &str, existing: &[String]) -> String {
+    // container names are DNS labels of at most 63 characters
+    let target: String = container_name.chars().take(63 - "hahaha--xxxxx".len()).collect();
+    let mut rng = rand::thread_rng();
+    loop {
+        let suffix: String = (0..5)
+            .map(|_| char::from(NAME_SUFFIX_CHARS[rng.gen_range(0..NAME_SUFFIX_CHARS.len())]))
+            .collect();
+        let name = format!("hahaha-{target}-{suffix}");
+        if !existing.contains(&name) {
+            return name;
+        }
+    }
 }
 
 async fn shutdown_portforward(
     pod: &Api<Pod>,
-    method: &Method,
-    path: &Uri,
-    port: u16,
+    request: &HttpRequest,
     pod_name: &str,
     container_name: &str,
 ) -> anyhow::Result<()> {
+    let HttpRequest { method, path, port, .. } = request;
+    let port = *port;
+    let stream = portforward_stream(pod, port, pod_name).await?;
+    send_http(stream, "127.0.0.1", request, pod_name).await?;
+    info!("{pod_name}: sent HTTP request `{method} {path}` at port {port} to {container_name}",);
+    Ok(())
+}
+
+/// Opens a port-forward to a single port in a pod
+async fn portforward_stream(
+    pod: &Api<Pod>,
+    port: u16,
+    pod_name: &str,
+) -> anyhow::Result<impl AsyncRead + AsyncWrite + Unpin + Send + 'static> {
     let mut pf = pod.portforward(pod_name, &[port]).await?;
-    let (mut sender, connection) = match pf.take_stream(port) {
-        None => return Err(anyhow!(format!("Unable to attach to port: {port}"))),
-        Some(s) => hyper::client::conn::handshake(s).await?,
+    match pf.take_stream(port) {
+        None => Err(anyhow!(format!("{pod_name}: Unable to attach to port: {port}"))),
+        Some(s) => Ok(s),
+    }
+}
+
+async fn shutdown_pod_ip(
+    pod: &Api<Pod>,
+    request: &HttpRequest,
+    pod_name: &str,
+    container_name: &str,
+) -> anyhow::Result<()> {
+    let HttpRequest { method, path, port, .. } = request;
+    let port = *port;
+    let Some(pod_ip) = pod.get(pod_name).await?.status.and_then(|status| status.pod_ip) else {
+        return Err(anyhow!(format!("{pod_name}: pod has no IP address")));
+    };
+    let stream = TcpStream::connect((pod_ip.as_str(), port)).await.map_err(|e| {
+        anyhow!(format!(
+            "{pod_name}: HTTP request ({method} {path} at {pod_ip}:{port}) failed: {e}"
+        ))
+    })?;
+    send_http(stream, &pod_ip, request, pod_name).await?;
+    info!("{pod_name}: sent HTTP request `{method} {path}` at {pod_ip}:{port} to {container_name}",);
+    Ok(())
+}
+
+/// Sends an HTTP request over an established connection to `host`, with TLS if the request asks for it
+async fn send_http<S>(stream: S, host: &str, request: &HttpRequest, pod_name: &str) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let Some(tls) = &request.tls else {
+        return send_request(stream, host, request, pod_name).await;
     };
+    let HttpRequest { method, path, port, .. } = request;
+    let stream = tls::connect(tls, host, stream).await.map_err(|e| {
+        anyhow!(format!(
+            "{pod_name}: HTTP request ({method} {path} at port {port}) failed: {e:#}"
+        ))
+    })?;
+    send_request(stream, host, request, pod_name).await
+}
+
+/// Sends an HTTP request over an established connection, failing unless the request accepts the response status
+async fn send_request<S>(stream: S, host: &str, request: &HttpRequest, pod_name: &str) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let HttpRequest { method, path, port, .. } = request;
+    let (mut sender, connection) = hyper::client::conn::handshake(stream).await?;
 
     let inner_pod_name = pod_name.to_string();
     tokio::spawn(async move {
         if let Err(e) = connection.await {
-            error!("{inner_pod_name}: error in portforward connection: {e}");
+            error!("{inner_pod_name}: error in HTTP connection: {e}");
         }
     });
 
-    let req = Request::builder()
+    let mut req = Request::builder()
         .uri(path)
         .header("Connection", "close")
-        .header("Host", "127.0.0.1")
+        .header("Host", host)
         .method(method)
-        .body(Body::from(""))?;
+        .body(Body::from(request.body.clone().unwrap_or_default()))?;
+    for (name, value) in &request.headers {
+        req.headers_mut()
+            .insert(HeaderName::from_str(name)?, HeaderValue::from_str(value)?);
+    }
 
     debug!("{pod_name}: sending HTTP request ({method} {path} at {port})");
 
-    let req_future = sender.send_request(req);
-
-    let (parts, body) = match tokio::time::timeout(Duration::from_secs(1), req_future).await {
-        Ok(req) => req?.into_parts(),
-        Err(_) => {
-            return Err(anyhow!(format!(
-                "{pod_name}: HTTP request ({method} {path} at port {port}) failed: request timeout"
-            )))
-        }
-    };
+    let (parts, body) = sender.send_request(req).await?.into_parts();
     let status_code = parts.status;
     debug!("{pod_name}: got status code {status_code}");
-    if status_code != 200 {
+    if !request.accepts(status_code.as_u16()) {
         let body_bytes = body::to_bytes(body).await?;
         let body_str = std::str::from_utf8(&body_bytes)?;
         return Err(anyhow!(format!(
             "{pod_name}: HTTP request ({method} {path} at port {port}) failed: code {status_code}: {body_str}"
         )));
     }
-    info!("{pod_name}: sent HTTP request `{method} {path}` at port {port} to {container_name}",);
     Ok(())
 }
+
+/// Writes a payload to an established connection, and reads the response until it matches `expect` if given
+async fn send_tcp<S>(
+    mut stream: S,
+    payload: &str,
+    expect: Option<&Regex>,
+    port: u16,
+    pod_name: &str,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    debug!("{pod_name}: writing {payload:?} at port {port}");
+    let exchange = async {
+        stream.write_all(payload.as_bytes()).await?;
+        stream.flush().await?;
+        let Some(expect) = expect else {
+            return Ok::<_, std::io::Error>(None);
+        };
+        let mut response = Vec::new();
+        let mut buf = [0; 1024];
+        loop {
+            let n = stream.read(&mut buf).await?;
+            response.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&response);
+            if expect.is_match(&text) {
+                return Ok(None);
+            }
+            if n == 0 {
+                return Ok(Some(text.into_owned()));
+            }
+        }
+    };
+    match exchange.await {
+        Ok(None) => Ok(()),
+        Ok(Some(response)) => Err(anyhow!(format!(
+            "{pod_name}: TCP payload at port {port} failed: response {response:?} doesn't match `{}`",
+            expect.map(Regex::as_str).unwrap_or_default()
+        ))),
+        Err(e) => Err(anyhow!(format!("{pod_name}: TCP payload at port {port} failed: {e}"))),
+    }
+}
+
+/// Calls a gRPC method with an empty request over an established connection, failing unless its status is OK
+async fn send_grpc<S>(stream: S, method: &str, port: u16, pod_name: &str) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = hyper::client::conn::Builder::new()
+        .http2_only(true)
+        .handshake(stream)
+        .await?;
+
+    let inner_pod_name = pod_name.to_string();
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            error!("{inner_pod_name}: error in gRPC connection: {e}");
+        }
+    });
+
+    // an empty message is an uncompressed frame with length 0
+    let req = Request::builder()
+        .uri(format!("http://127.0.0.1{method}"))
+        .method(Method::POST)
+        .header("content-type", "application/grpc")
+        .header("te", "trailers")
+        .body(Body::from(&[0u8; 5][..]))?;
+
+    debug!("{pod_name}: calling {method} at port {port}");
+
+    let call = async {
+        let mut response = sender.send_request(req).await?;
+        if response.status() != 200 {
+            return Err(anyhow!("HTTP status {}", response.status()));
+        }
+        // a failing call may only have headers, otherwise the status comes in the trailers
+        let mut status = grpc_status(response.headers());
+        if status.is_none() {
+            while let Some(data) = response.body_mut().data().await {
+                data?;
+            }
+            status = response.body_mut().trailers().await?.as_ref().and_then(grpc_status);
+        }
+        match status {
+            Some((0, _)) => Ok(()),
+            Some((code, message)) => Err(anyhow!("grpc-status {code}: {message}")),
+            None => Err(anyhow!("no grpc-status in response")),
+        }
+    };
+    call.await
+        .map_err(|e| anyhow!(format!("{pod_name}: gRPC call ({method} at port {port}) failed: {e}")))
+}
+
+fn grpc_status(headers: &HeaderMap) -> Option<(u32, String)> {
+    let code = headers.get("grpc-status")?.to_str().ok()?.parse().ok()?;
+    let message = headers
+        .get("grpc-message")
+        .and_then(|m| m.to_str().ok())
+        .unwrap_or_default();
+    Some((code, message.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::{Retry, Tls};
+    use hyper::{service::service_fn, Response, StatusCode};
+    use std::{
+        collections::BTreeMap,
+        convert::Infallible,
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+    use tokio::net::TcpListener;
+
+    /// Serves a single connection on a local port, answering with `status` and echoing what it got in the body
+    async fn serve_once(status: StatusCode) -> (TcpStream, tokio::task::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve(stream, status).await;
+        });
+        (TcpStream::connect(addr).await.unwrap(), server)
+    }
+
+    /// Like `serve_once`, but with TLS using a new self-signed certificate for `localhost`
+    ///
+    /// Returns the path to the certificate in PEM format as well.
+    async fn serve_tls_once(status: StatusCode) -> (TcpStream, PathBuf) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let path = std::env::temp_dir().join(format!(
+            "hahaha-ca-{}-{}.pem",
+            std::process::id(),
+            CA_FILES.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::write(&path, cert.serialize_pem().unwrap()).unwrap();
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![rustls::Certificate(cert.serialize_der().unwrap())],
+                rustls::PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            if let Ok(stream) = acceptor.accept(stream).await {
+                serve(stream, status).await;
+            }
+        });
+        (TcpStream::connect(addr).await.unwrap(), path)
+    }
+
+    static CA_FILES: AtomicUsize = AtomicUsize::new(0);
+
+    async fn serve<S>(stream: S, status: StatusCode)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let service = service_fn(move |req: Request<Body>| async move {
+            let auth = req.headers().get("Authorization").cloned();
+            let host = req.headers().get("Host").cloned();
+            let body = body::to_bytes(req.into_body()).await.unwrap();
+            let echo = format!(
+                "auth={:?} host={:?} body={}",
+                auth,
+                host,
+                String::from_utf8_lossy(&body)
+            );
+            Ok::<_, Infallible>(Response::builder().status(status).body(Body::from(echo)).unwrap())
+        });
+        hyper::server::conn::Http::new()
+            .serve_connection(stream, service)
+            .await
+            .unwrap();
+    }
+
+    fn request() -> HttpRequest {
+        HttpRequest::new(Method::POST, "/quitquitquit".parse().unwrap(), 8080)
+    }
+
+    #[tokio::test]
+    async fn action_timeout_covers_setup() {
+        // an API server that never answers, so that the port-forward is never set up
+        let service = tower::service_fn(|_: Request<Body>| {
+            std::future::pending::<Result<hyper::Response<Body>, std::convert::Infallible>>()
+        });
+        let pod: Api<Pod> = Api::namespaced(kube::Client::new(service, "default"), "default");
+        let action: Action = serde_json::from_value(json!({
+            "portforward": {"method": "POST", "path": "/quit", "port": 8080, "timeout": "100ms"}
+        }))
+        .unwrap();
+
+        let err = tokio::time::timeout(Duration::from_secs(5), shutdown_action(&pod, &action, "pod", "sidecar"))
+            .await
+            .expect("the action should time out by itself")
+            .unwrap_err();
+        assert!(err.to_string().contains("timed out after 100ms"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn failed_actions_are_left_to_the_caller_to_retry() {
+        let service = tower::service_fn(|_: Request<Body>| {
+            std::future::pending::<Result<hyper::Response<Body>, std::convert::Infallible>>()
+        });
+        let pod: Api<Pod> = Api::namespaced(kube::Client::new(service, "default"), "default");
+        let action: Action = serde_json::from_value(json!({
+            "portforward": {
+                "method": "POST", "path": "/quit", "port": 8080, "timeout": "10ms",
+                "retry": {"attempts": 1, "backoff": "1h"},
+            }
+        }))
+        .unwrap();
+        let actions = [action];
+
+        // the backoff is far longer than the test may take, so it mustn't be waited for here
+        let err = tokio::time::timeout(
+            Duration::from_secs(5),
+            shutdown_pod(&pod, &actions, 0, "pod", "sidecar"),
+        )
+        .await
+        .expect("the retry should be left to the caller")
+        .unwrap_err();
+        let retry = err.downcast::<RetryLater>().unwrap();
+        assert_eq!((retry.action, retry.retried), (0, 1));
+        assert!(retry.delay >= Retry::MAX_BACKOFF / 2, "{:?}", retry.delay);
+
+        let err = shutdown_pod(&pod, &actions, 1, "pod", "sidecar").await.unwrap_err();
+        assert!(err.downcast_ref::<RetryLater>().is_none(), "{}", err);
+    }
+
+    #[test]
+    fn ephemeral_container_names_are_unique_and_valid() {
+        let name = ephemeral_container_name("cloudsql-proxy", &[]);
+        assert!(name.starts_with("hahaha-cloudsql-proxy-"), "{}", name);
+        assert_eq!(name.len(), "hahaha-cloudsql-proxy-".len() + 5);
+        assert_ne!(
+            name,
+            ephemeral_container_name("cloudsql-proxy", std::slice::from_ref(&name))
+        );
+
+        let long = ephemeral_container_name(&"a".repeat(100), &[]);
+        assert_eq!(long.len(), 63);
+        assert!(long
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-'));
+    }
+
+    #[test]
+    fn exec_output_is_trimmed_and_truncated() {
+        assert_eq!(exec_output(b"", b" \n"), None);
+        assert_eq!(exec_output(b"bye\n", b""), Some("stdout: bye".into()));
+        assert_eq!(
+            exec_output(b"ok", b"kill: (1) - No such process\n"),
+            Some("stdout: ok, stderr: kill: (1) - No such process".into())
+        );
+        let long = "å".repeat(MAX_EXEC_OUTPUT + 1);
+        assert_eq!(
+            exec_output(long.as_bytes(), b""),
+            Some(format!("stdout: {}…", &long[..long.len() - "å".len()]))
+        );
+    }
+
+    #[test]
+    fn exec_status_fails_on_non_zero_exit_code() {
+        let status = |json: serde_json::Value| Some(serde_json::from_value::<Status>(json).unwrap());
+        assert!(exec_status(status(json!({"status": "Success"}))).is_ok());
+        let err = exec_status(status(json!({
+            "status": "Failure",
+            "reason": "NonZeroExitCode",
+            "message": "command terminated with non-zero exit code: error executing command [kill 1], exit code 1",
+            "details": {"causes": [{"reason": "ExitCode", "message": "1"}]},
+        })))
+        .unwrap_err();
+        assert_eq!(err.to_string(), "exited with code 1");
+        let err = exec_status(status(json!({"status": "Failure", "message": "container not found"}))).unwrap_err();
+        assert_eq!(err.to_string(), "failed: container not found");
+    }
+
+    #[test]
+    fn exec_status_leaves_missing_status_to_termination_check() {
+        // e.g. `kill -s KILL 1`, which takes the exec stream down with the container before a status is sent
+        assert!(exec_status(None).is_ok());
+    }
+
+    #[tokio::test]
+    async fn send_http_accepts_200_by_default() {
+        let (stream, server) = serve_once(StatusCode::OK).await;
+        send_http(stream, "127.0.0.1", &request(), "pod").await.unwrap();
+        server.await.unwrap();
+
+        let (stream, _) = serve_once(StatusCode::NO_CONTENT).await;
+        let err = send_http(stream, "127.0.0.1", &request(), "pod").await.unwrap_err();
+        assert!(err.to_string().contains("code 204"), "unexpected error: {}", err);
+    }
+
+    #[tokio::test]
+    async fn send_http_accepts_configured_statuses() {
+        let mut request = request();
+        request.statuses = vec![202, 204];
+
+        let (stream, _) = serve_once(StatusCode::NO_CONTENT).await;
+        send_http(stream, "127.0.0.1", &request, "pod").await.unwrap();
+
+        let (stream, _) = serve_once(StatusCode::OK).await;
+        let err = send_http(stream, "127.0.0.1", &request, "pod").await.unwrap_err();
+        assert!(err.to_string().contains("code 200"), "unexpected error: {}", err);
+    }
+
+    #[tokio::test]
+    async fn send_http_sends_headers_and_body() {
+        let mut request = request();
+        request.headers = BTreeMap::from([
+            ("Authorization".into(), "Bearer secret".into()),
+            ("Host".into(), "sidecar.local".into()),
+        ]);
+        request.body = Some(r#"{"graceful":true}"#.into());
+        request.statuses = vec![418];
+
+        // the echo ends up in the error, since 200 isn't accepted
+        let (stream, _) = serve_once(StatusCode::OK).await;
+        let err = send_http(stream, "127.0.0.1", &request, "pod").await.unwrap_err();
+        let expected = r#"auth=Some("Bearer secret") host=Some("sidecar.local") body={"graceful":true}"#;
+        assert!(err.to_string().contains(expected), "unexpected error: {}", err);
+    }
+
+    #[tokio::test]
+    async fn send_http_verifies_tls_with_ca_file() {
+        let (stream, ca_file) = serve_tls_once(StatusCode::OK).await;
+        let mut request = request();
+        request.tls = Some(Tls {
+            ca_file: Some(ca_file),
+            server_name: Some("localhost".into()),
+            ..Default::default()
+        });
+        send_http(stream, "127.0.0.1", &request, "pod").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn send_http_rejects_untrusted_or_mismatched_certificates() {
+        let (stream, _) = serve_tls_once(StatusCode::OK).await;
+        let mut request = request();
+        request.tls = Some(Tls {
+            server_name: Some("localhost".into()),
+            ..Default::default()
+        });
+        let err = send_http(stream, "127.0.0.1", &request, "pod").await.unwrap_err();
+        assert!(err.to_string().contains("TLS handshake"), "unexpected error: {}", err);
+
+        // the certificate is for localhost, not the address that is connected to
+        let (stream, ca_file) = serve_tls_once(StatusCode::OK).await;
+        request.tls = Some(Tls {
+            ca_file: Some(ca_file),
+            ..Default::default()
+        });
+        let err = send_http(stream, "127.0.0.1", &request, "pod").await.unwrap_err();
+        assert!(err.to_string().contains("TLS handshake"), "unexpected error: {}", err);
+    }
+
+    #[tokio::test]
+    async fn send_http_can_skip_tls_verification() {
+        let (stream, _) = serve_tls_once(StatusCode::OK).await;
+        let mut request = request();
+        request.tls = Some(Tls {
+            insecure_skip_verify: true,
+            ..Default::default()
+        });
+        send_http(stream, "127.0.0.1", &request, "pod").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn send_tcp_matches_response() {
+        let serve = || async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 5];
+                stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"quit\n");
+                stream.write_all(b"bye\n").await.unwrap();
+            });
+            TcpStream::connect(addr).await.unwrap()
+        };
+
+        send_tcp(serve().await, "quit\n", None, 9000, "pod").await.unwrap();
+        let expect = Regex::new("^bye").unwrap();
+        send_tcp(serve().await, "quit\n", Some(&expect), 9000, "pod")
+            .await
+            .unwrap();
+        let expect = Regex::new("^ok").unwrap();
+        let err = send_tcp(serve().await, "quit\n", Some(&expect), 9000, "pod")
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains(r#"response "bye\n" doesn't match `^ok`"#),
+            "unexpected error: {}",
+            err
+        );
+    }
+
+    /// Serves a single gRPC call, answering with `code` in the trailers, or in the headers if `trailers_only`
+    async fn serve_grpc_once(code: u32, trailers_only: bool) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service = service_fn(move |req: Request<Body>| async move {
+                assert_eq!(req.uri().path(), "/my.package.Admin/Shutdown");
+                assert_eq!(req.headers()["content-type"], "application/grpc");
+                assert_eq!(&body::to_bytes(req.into_body()).await.unwrap()[..], &[0; 5]);
+                let response = Response::builder().header("content-type", "application/grpc");
+                if trailers_only {
+                    return Ok::<_, Infallible>(
+                        response
+                            .header("grpc-status", code.to_string())
+                            .header("grpc-message", "not now")
+                            .body(Body::empty())
+                            .unwrap(),
+                    );
+                }
+                let (mut sender, body) = Body::channel();
+                tokio::spawn(async move {
+                    let mut trailers = HeaderMap::new();
+                    trailers.insert("grpc-status", code.into());
+                    sender.send_trailers(trailers).await.unwrap();
+                });
+                Ok(response.body(body).unwrap())
+            });
+            hyper::server::conn::Http::new()
+                .http2_only(true)
+                .serve_connection(stream, service)
+                .await
+                .unwrap();
+        });
+        TcpStream::connect(addr).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn send_grpc_checks_grpc_status() {
+        let method = "/my.package.Admin/Shutdown";
+        send_grpc(serve_grpc_once(0, false).await, method, 9000, "pod")
+            .await
+            .unwrap();
+
+        let err = send_grpc(serve_grpc_once(14, false).await, method, 9000, "pod")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("grpc-status 14"), "unexpected error: {}", err);
+
+        let err = send_grpc(serve_grpc_once(12, true).await, method, 9000, "pod")
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("grpc-status 12: not now"),
+            "unexpected error: {}",
+            err
+        );
+    }
+}
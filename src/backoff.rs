@@ -0,0 +1,36 @@
+use rand::Rng;
+use std::time::Duration;
+
+/// Exponential backoff with jitter
+///
+/// The delay doubles for every `attempt` (counting from 0), up to `max`,
+/// and a random part of up to half of it is taken off so retries from many pods don't line up.
+pub fn jittered(base: Duration, max: Duration, attempt: u32) -> Duration {
+    let delay = base.saturating_mul(2u32.saturating_pow(attempt)).min(max);
+    let jitter = rand::thread_rng().gen_range(0.0..=0.5);
+    delay.mul_f64(1.0 - jitter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_doubles_with_jitter_up_to_max() {
+        let base = Duration::from_secs(1);
+        let max = Duration::from_secs(10);
+        for (attempt, expected) in [(0, 1), (1, 2), (2, 4), (3, 8), (4, 10), (40, 10)] {
+            let expected = Duration::from_secs(expected);
+            for _ in 0..100 {
+                let delay = jittered(base, max, attempt);
+                assert!(
+                    delay <= expected && delay >= expected / 2,
+                    "attempt {}: {:?} not within jitter of {:?}",
+                    attempt,
+                    delay,
+                    expected
+                );
+            }
+        }
+    }
+}
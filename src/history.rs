@@ -0,0 +1,206 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long to remember a Pod that hasn't been acted on
+///
+/// Pods are normally forgotten when they have no running sidecars left, this only cleans up after deleted Pods.
+static RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// What has been done to the sidecars of the Pods that are currently being handled
+#[derive(Default)]
+pub struct History {
+    pods: Mutex<HashMap<String, PodHistory>>,
+    /// When things about Pods were first reported, which outlives forgetting them so that they're only reported once
+    reported: Mutex<HashMap<(String, &'static str), Instant>>,
+}
+
+struct PodHistory {
+    /// Set on the first shutdown attempt, so that deciding on a Pod doesn't count towards its last resort budget
+    attempts: Option<Attempts>,
+    updated_at: Instant,
+    sidecars: HashMap<String, SidecarHistory>,
+    /// Whether the decision to act on the Pod or not has been reported
+    decided: bool,
+    /// Sidecars whose shutdown has been planned in dry-run mode
+    planned: HashSet<String>,
+    /// Sidecars that have been reported as having no action
+    unsupported: HashSet<String>,
+    /// Sidecars whose invalid action annotation has been reported
+    invalid_annotations: HashSet<String>,
+    /// Sidecars with an action that failed and is to be retried
+    retries: HashMap<String, PlannedRetry>,
+}
+
+/// How hard the sidecars of a Pod have been tried to shut down
+#[derive(Clone, Copy, Debug)]
+pub struct Attempts {
+    /// When the first action was attempted
+    pub started_at: Instant,
+    /// How many times an action failed or a sidecar kept running after all its actions
+    pub failures: u32,
+}
+
+/// An action that failed for a sidecar, and is to be retried
+#[derive(Clone, Copy, Debug)]
+pub struct PlannedRetry {
+    /// The index in the action chain of the action to retry
+    pub action: usize,
+    /// How many times the action will have been retried
+    pub retried: u32,
+    /// When to retry the action
+    pub at: Instant,
+}
+
+/// What has been done to a single sidecar
+#[derive(Clone, Copy, Debug)]
+pub struct SidecarHistory {
+    /// When the last successful action was performed
+    pub acted_at: Instant,
+    /// The index in the action chain to escalate to if the sidecar doesn't terminate
+    pub next_action: usize,
+    /// Whether the sidecar has been reported as still running after the whole chain
+    pub unverified: bool,
+}
+
+impl History {
+    /// Get what has been done to a sidecar in a Pod
+    pub fn sidecar(&self, pod_key: &str, sidecar_name: &str) -> Option<SidecarHistory> {
+        let pods = self.pods.lock().unwrap();
+        pods.get(pod_key)?.sidecars.get(sidecar_name).copied()
+    }
+
+    /// Get how hard the sidecars of a Pod have been tried to shut down
+    pub fn attempts(&self, pod_key: &str) -> Option<Attempts> {
+        self.pods.lock().unwrap().get(pod_key)?.attempts
+    }
+
+    /// Record that an action succeeded for a sidecar in a Pod
+    pub fn record_shutdown(&self, pod_key: &str, sidecar_name: &str, next_action: usize) {
+        self.update(pod_key, |pod| {
+            pod.attempt();
+            pod.sidecars.insert(
+                sidecar_name.into(),
+                SidecarHistory {
+                    acted_at: Instant::now(),
+                    next_action,
+                    unverified: false,
+                },
+            );
+        });
+    }
+
+    /// Get the retry planned for a sidecar in a Pod
+    pub fn retry(&self, pod_key: &str, sidecar_name: &str) -> Option<PlannedRetry> {
+        let pods = self.pods.lock().unwrap();
+        pods.get(pod_key)?.retries.get(sidecar_name).copied()
+    }
+
+    /// Plan to retry an action for a sidecar in a Pod, or plan no retry with `None`
+    pub fn plan_retry(&self, pod_key: &str, sidecar_name: &str, retry: Option<PlannedRetry>) {
+        self.update(pod_key, |pod| match retry {
+            Some(retry) => {
+                pod.attempt();
+                pod.retries.insert(sidecar_name.into(), retry);
+            }
+            None => {
+                pod.retries.remove(sidecar_name);
+            }
+        });
+    }
+
+    /// Record that shutting down a sidecar in a Pod failed, returning the number of failures so far
+    pub fn record_failure(&self, pod_key: &str) -> u32 {
+        self.update(pod_key, |pod| {
+            let attempts = pod.attempt();
+            attempts.failures += 1;
+            attempts.failures
+        })
+    }
+
+    /// Record that a sidecar is still running after its whole action chain
+    ///
+    /// Returns `true` the first time it is called for a sidecar.
+    pub fn mark_unverified(&self, pod_key: &str, sidecar_name: &str) -> bool {
+        let mut pods = self.pods.lock().unwrap();
+        let Some(sidecar) = pods.get_mut(pod_key).and_then(|pod| pod.sidecars.get_mut(sidecar_name)) else {
+            return false;
+        };
+        !std::mem::replace(&mut sidecar.unverified, true)
+    }
+
+    /// Record that the decision to act on a Pod or not has been reported
+    ///
+    /// Returns `true` the first time it is called for a Pod.
+    pub fn mark_decided(&self, pod_key: &str) -> bool {
+        self.update(pod_key, |pod| !std::mem::replace(&mut pod.decided, true))
+    }
+
+    /// Record that shutting down a sidecar in a Pod has been planned in dry-run mode
+    ///
+    /// Returns `true` the first time it is called for a sidecar.
+    pub fn mark_planned(&self, pod_key: &str, sidecar_name: &str) -> bool {
+        self.update(pod_key, |pod| pod.planned.insert(sidecar_name.into()))
+    }
+
+    /// Record that a sidecar in a Pod has been reported as having no action
+    ///
+    /// Returns `true` the first time it is called for a sidecar.
+    pub fn mark_unsupported(&self, pod_key: &str, sidecar_name: &str) -> bool {
+        self.update(pod_key, |pod| pod.unsupported.insert(sidecar_name.into()))
+    }
+
+    /// Record that the invalid action annotation for a sidecar in a Pod has been reported
+    ///
+    /// Returns `true` the first time it is called for a sidecar.
+    pub fn mark_invalid_annotation(&self, pod_key: &str, sidecar_name: &str) -> bool {
+        self.update(pod_key, |pod| pod.invalid_annotations.insert(sidecar_name.into()))
+    }
+
+    /// Record that something about a Pod, named by `report`, has been reported
+    ///
+    /// Returns `true` the first time it is called for a Pod and `report`, even after the Pod has been forgotten.
+    pub fn first_report(&self, pod_key: &str, report: &'static str) -> bool {
+        let mut reported = self.reported.lock().unwrap();
+        reported.retain(|_, reported_at| reported_at.elapsed() < RETENTION);
+        let key = (pod_key.to_string(), report);
+        if reported.contains_key(&key) {
+            return false;
+        }
+        reported.insert(key, Instant::now());
+        true
+    }
+
+    /// Forget everything about a Pod
+    pub fn forget(&self, pod_key: &str) {
+        self.pods.lock().unwrap().remove(pod_key);
+    }
+
+    fn update<T>(&self, pod_key: &str, f: impl FnOnce(&mut PodHistory) -> T) -> T {
+        let mut pods = self.pods.lock().unwrap();
+        pods.retain(|_, pod| pod.updated_at.elapsed() < RETENTION);
+        let now = Instant::now();
+        let pod = pods.entry(pod_key.into()).or_insert_with(|| PodHistory {
+            attempts: None,
+            updated_at: now,
+            sidecars: HashMap::new(),
+            decided: false,
+            planned: HashSet::new(),
+            unsupported: HashSet::new(),
+            invalid_annotations: HashSet::new(),
+            retries: HashMap::new(),
+        });
+        pod.updated_at = now;
+        f(pod)
+    }
+}
+
+impl PodHistory {
+    /// Get the attempts to shut down the sidecars, starting them now if this is the first one
+    fn attempt(&mut self) -> &mut Attempts {
+        self.attempts.get_or_insert_with(|| Attempts {
+            started_at: Instant::now(),
+            failures: 0,
+        })
+    }
+}
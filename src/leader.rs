@@ -0,0 +1,231 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use futures::{stream, Stream};
+use k8s_openapi::{
+    api::coordination::v1::{Lease, LeaseSpec},
+    apimachinery::pkg::apis::meta::v1::MicroTime,
+    chrono::{self, DateTime, Utc},
+};
+use kube::{
+    api::{ObjectMeta, PostParams},
+    Api,
+};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::prometheus::LEADER;
+
+/// Leader election with a `Lease`, so that only one of several replicas acts on Pods
+///
+/// Replicas that aren't the leader keep watching Pods, so that they can take over without warming up first.
+#[derive(Clone)]
+pub struct LeaderElection {
+    api: Api<Lease>,
+    name: String,
+    identity: String,
+    lease_duration: Duration,
+}
+
+impl LeaderElection {
+    pub fn new(api: Api<Lease>, name: String, identity: String, lease_duration: Duration) -> Self {
+        Self {
+            api,
+            name,
+            identity,
+            lease_duration,
+        }
+    }
+
+    /// Keep acquiring or renewing the `Lease`, publishing whether this replica is the leader to `sender`
+    ///
+    /// The `Lease` is renewed three times per lease duration. Leadership is given up if the `Lease` couldn't be
+    /// renewed within two thirds of a lease duration, counted from before the last successful request, so that
+    /// this replica stops acting before another one can take the `Lease` over. Reconciles that are under way
+    /// stop as soon as leadership is given up, see `leadership_lost`.
+    pub async fn run(self, sender: watch::Sender<bool>) {
+        let period = self.lease_duration / 3;
+        let renew_deadline = self.lease_duration * 2 / 3;
+        let mut interval = tokio::time::interval(period);
+        let mut renewed_at: Option<Instant> = None;
+        loop {
+            interval.tick().await;
+            let started = Instant::now();
+            // a slow request mustn't keep this replica the leader past the renew deadline
+            let timeout = renewed_at.map_or(period, |at| {
+                period.min((at + renew_deadline).saturating_duration_since(started))
+            });
+            match tokio::time::timeout(timeout, self.try_acquire()).await {
+                Ok(Ok(true)) => renewed_at = Some(started),
+                Ok(Ok(false)) => renewed_at = None,
+                Ok(Err(e)) => warn!("could not renew lease {}: {e:#}", self.name),
+                Err(_) => warn!("could not renew lease {}: timed out after {timeout:?}", self.name),
+            }
+            let leader = renewed_at.is_some_and(|at| at.elapsed() < renew_deadline);
+            LEADER.set(i64::from(leader));
+            sender.send_if_modified(|current| {
+                if *current == leader {
+                    return false;
+                }
+                match leader {
+                    true => info!("{} became the leader", self.identity),
+                    false => info!("{} is no longer the leader", self.identity),
+                }
+                *current = leader;
+                true
+            });
+        }
+    }
+
+    /// Give up the `Lease` if this replica holds it, so that another replica can take over right away
+    pub async fn release(&self) -> Result<()> {
+        let Some(mut lease) = self.api.get_opt(&self.name).await? else {
+            return Ok(());
+        };
+        let Some(spec) = lease.spec.as_mut() else {
+            return Ok(());
+        };
+        if spec.holder_identity.as_deref() != Some(&self.identity) {
+            return Ok(());
+        }
+        spec.holder_identity = None;
+        self.api.replace(&self.name, &PostParams::default(), &lease).await?;
+        LEADER.set(0);
+        info!("{} released lease {}", self.identity, self.name);
+        Ok(())
+    }
+
+    /// Try to acquire or renew the `Lease`, returning whether this replica holds it
+    async fn try_acquire(&self) -> Result<bool> {
+        let current = self.api.get_opt(&self.name).await?;
+        let Some(spec) = next_lease(
+            current.as_ref().and_then(|lease| lease.spec.as_ref()),
+            &self.identity,
+            Utc::now(),
+            self.lease_duration,
+        ) else {
+            return Ok(false);
+        };
+        let lease = Lease {
+            metadata: ObjectMeta {
+                name: Some(self.name.clone()),
+                // replacing with the resource version we saw means losing the race if another replica got there first
+                resource_version: current
+                    .as_ref()
+                    .and_then(|lease| lease.metadata.resource_version.clone()),
+                ..Default::default()
+            },
+            spec: Some(spec),
+        };
+        let result = match current {
+            Some(_) => self.api.replace(&self.name, &PostParams::default(), &lease).await,
+            None => self.api.create(&PostParams::default(), &lease).await,
+        };
+        match result {
+            Ok(_) => Ok(true),
+            Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Work out the `LeaseSpec` for `identity` to hold the `Lease`, or `None` if another replica holds it
+///
+/// A `Lease` that hasn't been renewed within its duration is free for the taking.
+fn next_lease(
+    current: Option<&LeaseSpec>,
+    identity: &str,
+    now: DateTime<Utc>,
+    lease_duration: Duration,
+) -> Option<LeaseSpec> {
+    let holder = current
+        .and_then(|spec| spec.holder_identity.as_deref())
+        .filter(|holder| !holder.is_empty());
+    let expired = current.is_none_or(|spec| {
+        let duration = spec
+            .lease_duration_seconds
+            .map_or(lease_duration.as_secs() as i64, i64::from);
+        spec.renew_time
+            .as_ref()
+            .is_none_or(|renewed| renewed.0 + chrono::Duration::seconds(duration) < now)
+    });
+    let transitions = current.and_then(|spec| spec.lease_transitions).unwrap_or(0);
+    let (acquire_time, lease_transitions) = match holder {
+        Some(holder) if holder == identity => (current.and_then(|spec| spec.acquire_time.clone()), transitions),
+        Some(_) if !expired => return None,
+        _ if current.is_none() => (Some(MicroTime(now)), 0),
+        _ => (Some(MicroTime(now)), transitions + 1),
+    };
+    Some(LeaseSpec {
+        holder_identity: Some(identity.into()),
+        lease_duration_seconds: Some(lease_duration.as_secs() as i32),
+        acquire_time,
+        renew_time: Some(MicroTime(now)),
+        lease_transitions: Some(lease_transitions),
+    })
+}
+
+/// A stream that yields whenever this replica becomes the leader
+pub fn leadership_gained(leader: watch::Receiver<bool>) -> impl Stream<Item = ()> + Send + Sync {
+    stream::unfold(leader, |mut leader| async move {
+        loop {
+            leader.changed().await.ok()?;
+            if *leader.borrow_and_update() {
+                return Some(((), leader));
+            }
+        }
+    })
+}
+
+/// Wait until this replica is no longer the leader, which never happens if nothing can take leadership away
+pub async fn leadership_lost(mut leader: watch::Receiver<bool>) {
+    if leader.wait_for(|leader| !leader).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leases_are_only_taken_when_free_or_expired() {
+        let now = Utc::now();
+        let duration = Duration::from_secs(15);
+        let held_by = |holder: &str, renewed_secs_ago: i64| LeaseSpec {
+            holder_identity: Some(holder.into()),
+            lease_duration_seconds: Some(15),
+            acquire_time: Some(MicroTime(now - chrono::Duration::hours(1))),
+            renew_time: Some(MicroTime(now - chrono::Duration::seconds(renewed_secs_ago))),
+            lease_transitions: Some(3),
+        };
+
+        // a new lease is acquired
+        let spec = next_lease(None, "a", now, duration).unwrap();
+        assert_eq!(spec.holder_identity.as_deref(), Some("a"));
+        assert_eq!(spec.acquire_time, Some(MicroTime(now)));
+        assert_eq!(spec.lease_transitions, Some(0));
+
+        // our own lease is renewed without a transition
+        let spec = next_lease(Some(&held_by("a", 5)), "a", now, duration).unwrap();
+        assert_eq!(spec.renew_time, Some(MicroTime(now)));
+        assert_eq!(spec.acquire_time, held_by("a", 5).acquire_time);
+        assert_eq!(spec.lease_transitions, Some(3));
+
+        // another replica's lease is left alone until it expires
+        assert_eq!(next_lease(Some(&held_by("b", 5)), "a", now, duration), None);
+        let spec = next_lease(Some(&held_by("b", 20)), "a", now, duration).unwrap();
+        assert_eq!(spec.holder_identity.as_deref(), Some("a"));
+        assert_eq!(spec.acquire_time, Some(MicroTime(now)));
+        assert_eq!(spec.lease_transitions, Some(4));
+
+        // a released lease is free right away
+        let released = LeaseSpec {
+            holder_identity: None,
+            ..held_by("b", 5)
+        };
+        let spec = next_lease(Some(&released), "a", now, duration).unwrap();
+        assert_eq!(spec.holder_identity.as_deref(), Some("a"));
+        assert_eq!(spec.lease_transitions, Some(4));
+    }
+}
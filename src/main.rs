@@ -2,27 +2,56 @@
 extern crate lazy_static;
 
 use futures::StreamExt;
-use k8s_openapi::api::core::v1::Pod;
+use k8s_openapi::api::core::v1::{Namespace, Pod};
 use kube::{
     api::Api,
-    runtime::{events::Reporter, watcher, Controller},
-    Client,
+    runtime::{
+        events::Reporter,
+        reflector::{self, Store},
+        watcher, Controller, WatchStreamExt,
+    },
+    Client, Resource, ResourceExt,
 };
+use serde::de::DeserializeOwned;
 use std::env;
+use std::fmt::{Debug, Display};
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
-use tokio::sync::Notify;
-use tracing::{debug, warn};
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+use tracing::{debug, info, warn};
 use tracing_subscriber::prelude::*;
 
 mod actions;
 mod api;
+mod backoff;
+mod history;
+mod leader;
+mod namespaces;
 mod pod;
+mod policy;
 mod prometheus;
 mod reconciler;
+mod tls;
+mod watches;
 
-use crate::prometheus::prometheus_server;
+use crate::actions::Transport;
+use crate::leader::LeaderElection;
+use crate::namespaces::NamespaceFilter;
+use crate::pod::{MainContainerResolution, TerminationPolicy};
+use crate::policy::{ClusterSidecarShutdownPolicy, SidecarShutdownPolicy};
+use crate::prometheus::{prometheus_server, LEADER};
+use crate::watches::Watch;
 
 static PROMETHEUS_PORT: u16 = 8999;
+static ACTIONS_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+static DEFAULT_VERIFY_DEADLINE: Duration = Duration::from_secs(30);
+static DEFAULT_LAST_RESORT_ATTEMPTS: u32 = 5;
+static DEFAULT_LAST_RESORT_BUDGET: Duration = Duration::from_secs(30 * 60);
+static DEFAULT_LEASE_DURATION: Duration = Duration::from_secs(15);
+static MIN_LEASE_DURATION: Duration = Duration::from_secs(3);
+static SYNC_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -36,12 +65,77 @@ async fn main() -> anyhow::Result<()> {
         .with(format_layer)
         .init();
 
-    let label_env = env::var("WATCH_SELECTOR").unwrap_or("nais.io/naisjob=true".to_string());
+    let watches = match env::var("WATCHES_FILE") {
+        Ok(path) => watches::load(&PathBuf::from(path))?,
+        Err(_) => vec![Watch::from_selector(
+            env::var("WATCH_SELECTOR").unwrap_or("nais.io/naisjob=true".to_string()),
+        )],
+    };
+    let verify_deadline = env_duration("SHUTDOWN_VERIFY_DEADLINE_SECONDS", DEFAULT_VERIFY_DEADLINE)?;
+    let last_resort_attempts = env_or("LAST_RESORT_MAX_ATTEMPTS", DEFAULT_LAST_RESORT_ATTEMPTS)?;
+    let last_resort_budget = env_duration("LAST_RESORT_BUDGET_SECONDS", DEFAULT_LAST_RESORT_BUDGET)?;
+    let http_transport = env_or("HTTP_TRANSPORT", Transport::default())?;
+    let termination_policy = env_or("TERMINATION_POLICY", TerminationPolicy::default())?;
+    let grace_period = env_duration("SHUTDOWN_GRACE_PERIOD_SECONDS", Duration::ZERO)?;
+    let main_containers = env_or("MAIN_CONTAINER_RESOLUTION", MainContainerResolution::default())?;
+    let dry_run = env_or("DRY_RUN", false)?;
+    if dry_run {
+        info!("dry run: only reporting what would be done to sidecars");
+    }
+    let namespace_filter = NamespaceFilter {
+        include: env_list("INCLUDE_NAMESPACES"),
+        include_selector: env_opt("INCLUDE_NAMESPACE_SELECTOR")?,
+        exclude: env_list("EXCLUDE_NAMESPACES"),
+        exclude_selector: env_opt("EXCLUDE_NAMESPACE_SELECTOR")?,
+    };
+    let leader_election = env_or("LEADER_ELECTION", false)?;
+    let lease_name = env::var("LEADER_ELECTION_LEASE_NAME").unwrap_or("hahaha".to_string());
+    let lease_duration = env_duration("LEADER_ELECTION_LEASE_DURATION_SECONDS", DEFAULT_LEASE_DURATION)?;
+    if lease_duration < MIN_LEASE_DURATION {
+        return Err(anyhow::anyhow!(
+            "invalid LEADER_ELECTION_LEASE_DURATION_SECONDS `{}`: must be at least {}",
+            lease_duration.as_secs(),
+            MIN_LEASE_DURATION.as_secs()
+        ));
+    }
 
-    let actions = actions::generate();
+    let actions = match env::var("ACTIONS_FILE") {
+        Ok(path) => {
+            let path = PathBuf::from(path);
+            let (sender, receiver) = watch::channel(Arc::new(actions::load(&path)?));
+            tokio::spawn(actions::watch(path, ACTIONS_RELOAD_INTERVAL, sender));
+            receiver
+        }
+        Err(_) => watch::channel(Arc::new(actions::defaults())).1,
+    };
+    info!("loaded {}", *actions.borrow());
     let client = Client::try_default().await?;
 
     let pods: Api<Pod> = Api::all(client.clone());
+    let policies = spawn_reflector(Api::all(client.clone()), |policy: &SidecarShutdownPolicy| {
+        policy.spec.table.parse().map(drop)
+    });
+    let cluster_policies = spawn_reflector(Api::all(client.clone()), |policy: &ClusterSidecarShutdownPolicy| {
+        policy.spec.table.parse().map(drop)
+    });
+    let namespaces = spawn_reflector(Api::<Namespace>::all(client.clone()), |_| Ok(()));
+    // acting on pods before the policies are known would miss the actions in them,
+    // but a watch that keeps failing mustn't keep HAHAHA from acting at all
+    info!("waiting for policies and namespaces to sync");
+    let synced = async {
+        tokio::try_join!(
+            policies.wait_until_ready(),
+            cluster_policies.wait_until_ready(),
+            namespaces.wait_until_ready()
+        )
+    };
+    match tokio::time::timeout(SYNC_TIMEOUT, synced).await {
+        Ok(result) => drop(result?),
+        Err(_) => warn!(
+            "policies and namespaces haven't synced after {}s, starting anyway",
+            SYNC_TIMEOUT.as_secs()
+        ),
+    }
 
     let h = hostname::get()?;
     let host_name = h.to_str().unwrap_or("hahaha-1337");
@@ -51,6 +145,23 @@ async fn main() -> anyhow::Result<()> {
         instance: Some(host_name.into()),
     };
 
+    // without leader election this replica is always the leader
+    let (leader_sender, leader) = watch::channel(!leader_election);
+    let election = leader_election.then(|| {
+        let api = Api::default_namespaced(client.clone());
+        info!(
+            "electing a leader with lease {lease_name} in {}",
+            client.default_namespace()
+        );
+        LeaderElection::new(api, lease_name, host_name.into(), lease_duration)
+    });
+    let election_task = election
+        .clone()
+        .map(|election| tokio::spawn(election.run(leader_sender)));
+    if election.is_none() {
+        LEADER.set(1);
+    }
+
     let shutdown = Arc::new(Notify::new());
     let shutdown_clone = shutdown.clone();
     let prom = tokio::spawn(async move {
@@ -59,7 +170,16 @@ async fn main() -> anyhow::Result<()> {
             .unwrap();
     });
 
-    Controller::new(pods, watcher::Config::default().labels(&label_env))
+    let fields = namespace_filter.field_selector();
+    if let Some(fields) = &fields {
+        info!("only watching pods with {fields}");
+    }
+    for watch in &watches {
+        info!("pods from {watch}");
+    }
+    let (reader, watch_stores, pods) = watches::watch_pods(pods, &watches, fields.as_deref());
+    Controller::for_stream(pods, reader)
+        .reconcile_all_on(leader::leadership_gained(leader.clone()))
         .shutdown_on_signal()
         .run(
             reconciler::reconcile,
@@ -68,6 +188,21 @@ async fn main() -> anyhow::Result<()> {
                 client,
                 reporter,
                 actions,
+                policies,
+                cluster_policies,
+                history: Default::default(),
+                verify_deadline,
+                namespaces,
+                last_resort_attempts,
+                last_resort_budget,
+                http_transport,
+                termination_policy,
+                grace_period,
+                main_containers,
+                dry_run,
+                namespace_filter,
+                watches: watches.into_iter().zip(watch_stores).collect(),
+                leader,
             }),
         )
         .for_each(|res| async move {
@@ -78,8 +213,116 @@ async fn main() -> anyhow::Result<()> {
         })
         .await;
 
+    // hand over to another replica right away instead of having it wait for the lease to expire
+    if let (Some(election), Some(task)) = (election, election_task) {
+        task.abort();
+        if let Err(e) = election.release().await {
+            warn!("could not release lease: {e:#}");
+        }
+    }
+
     // we're likely not ever reaching down here, but let's be nice about it if we do
     shutdown.notify_one();
     prom.await?;
     Ok(())
 }
+
+/// Read a number of seconds from an environment variable, falling back to `default` if it isn't set
+fn env_duration(name: &str, default: Duration) -> anyhow::Result<Duration> {
+    Ok(Duration::from_secs(env_or(name, default.as_secs())?))
+}
+
+/// Parse an environment variable, falling back to `default` if it isn't set
+fn env_or<T>(name: &str, default: T) -> anyhow::Result<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|e| anyhow::anyhow!("invalid {name} `{value}`: {e}")),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Parse an environment variable if it is set to anything but an empty value
+fn env_opt<T>(name: &str) -> anyhow::Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    env::var(name)
+        .ok()
+        .filter(|value| !value.trim().is_empty())
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|e| anyhow::anyhow!("invalid {name} `{value}`: {e}"))
+        })
+        .transpose()
+}
+
+/// Read a list separated by `,` from an environment variable, which is empty if it isn't set
+fn env_list(name: &str) -> Vec<String> {
+    env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Keep a `Store` of all `K`s up to date in the background
+///
+/// Objects that fail `check` are logged as they come in, and kept in the `Store` for the users to skip.
+fn spawn_reflector<K>(api: Api<K>, check: fn(&K) -> anyhow::Result<()>) -> Store<K>
+where
+    K: Resource + Clone + Debug + DeserializeOwned + Send + Sync + 'static,
+    K::DynamicType: Default + Eq + std::hash::Hash + Clone,
+{
+    let (store, writer) = reflector::store();
+    let stream = watcher(api, watcher::Config::default())
+        .default_backoff()
+        .reflect(writer)
+        .touched_objects();
+    tokio::spawn(stream.for_each(move |res| async move {
+        let kind = K::kind(&K::DynamicType::default()).to_string();
+        match res {
+            Ok(object) => {
+                if let Err(e) = check(&object) {
+                    let name = match object.namespace() {
+                        Some(namespace) => format!("{namespace}/{}", object.name_any()),
+                        None => object.name_any(),
+                    };
+                    warn!("ignoring invalid {kind} {name}: {e:#}");
+                }
+            }
+            Err(e) => warn!("watch of {kind} failed: {e}"),
+        }
+    }));
+    store
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::namespaces::LabelSelector;
+
+    #[test]
+    fn empty_env_opt_means_unset() {
+        env::set_var("HAHAHA_TEST_EMPTY_SELECTOR", "");
+        assert_eq!(env_opt::<LabelSelector>("HAHAHA_TEST_EMPTY_SELECTOR").unwrap(), None);
+        env::set_var("HAHAHA_TEST_BLANK_SELECTOR", "  ");
+        assert_eq!(env_opt::<LabelSelector>("HAHAHA_TEST_BLANK_SELECTOR").unwrap(), None);
+        assert_eq!(env_opt::<LabelSelector>("HAHAHA_TEST_UNSET_SELECTOR").unwrap(), None);
+        env::set_var("HAHAHA_TEST_SELECTOR", "team=a");
+        assert_eq!(
+            env_opt::<LabelSelector>("HAHAHA_TEST_SELECTOR").unwrap(),
+            Some("team=a".parse().unwrap())
+        );
+        env::set_var("HAHAHA_TEST_INVALID_SELECTOR", "=a");
+        assert!(env_opt::<LabelSelector>("HAHAHA_TEST_INVALID_SELECTOR").is_err());
+    }
+}
@@ -0,0 +1,229 @@
+use anyhow::{anyhow, Result};
+use k8s_openapi::api::core::v1::Namespace;
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+/// A Kubernetes label selector, e.g. `team=a,env in (dev,test),!legacy`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LabelSelector(Vec<Requirement>);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Requirement {
+    Exists(String),
+    NotExists(String),
+    In(String, Vec<String>),
+    NotIn(String, Vec<String>),
+}
+
+impl LabelSelector {
+    /// Whether a set of labels fulfills all the requirements of the selector
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        self.0.iter().all(|requirement| match requirement {
+            Requirement::Exists(key) => labels.contains_key(key),
+            Requirement::NotExists(key) => !labels.contains_key(key),
+            Requirement::In(key, values) => labels.get(key).is_some_and(|value| values.contains(value)),
+            Requirement::NotIn(key, values) => labels.get(key).is_none_or(|value| !values.contains(value)),
+        })
+    }
+}
+
+impl fmt::Display for LabelSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let requirements: Vec<String> = self
+            .0
+            .iter()
+            .map(|requirement| match requirement {
+                Requirement::Exists(key) => key.clone(),
+                Requirement::NotExists(key) => format!("!{key}"),
+                Requirement::In(key, values) if values.len() == 1 => format!("{key}={}", values[0]),
+                Requirement::NotIn(key, values) if values.len() == 1 => format!("{key}!={}", values[0]),
+                Requirement::In(key, values) => format!("{key} in ({})", values.join(",")),
+                Requirement::NotIn(key, values) => format!("{key} notin ({})", values.join(",")),
+            })
+            .collect();
+        f.write_str(&requirements.join(","))
+    }
+}
+
+impl FromStr for LabelSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        // commas separate requirements, except inside the parentheses of `in` and `notin`
+        let mut requirements = Vec::new();
+        let (mut depth, mut start) = (0, 0);
+        for (i, c) in s.char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                ',' if depth == 0 => {
+                    requirements.push(&s[start..i]);
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+        requirements.push(&s[start..]);
+        let requirements = requirements
+            .into_iter()
+            .map(str::trim)
+            .filter(|r| !r.is_empty())
+            .map(parse_requirement)
+            .collect::<Result<Vec<_>>>()?;
+        if requirements.is_empty() {
+            return Err(anyhow!("label selector must not be empty"));
+        }
+        Ok(Self(requirements))
+    }
+}
+
+fn parse_requirement(s: &str) -> Result<Requirement> {
+    let key = |key: &str| {
+        let key = key.trim();
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            return Err(anyhow!("invalid label selector requirement `{s}`"));
+        }
+        Ok(key.to_string())
+    };
+    let values = |values: &str| -> Result<Vec<String>> {
+        let values = values
+            .trim()
+            .strip_prefix('(')
+            .and_then(|v| v.strip_suffix(')'))
+            .ok_or_else(|| anyhow!("values in `{s}` must be in parentheses"))?;
+        Ok(values.split(',').map(|v| v.trim().to_string()).collect())
+    };
+    if let Some((k, v)) = s.split_once(" notin ") {
+        return Ok(Requirement::NotIn(key(k)?, values(v)?));
+    }
+    if let Some((k, v)) = s.split_once(" in ") {
+        return Ok(Requirement::In(key(k)?, values(v)?));
+    }
+    if let Some((k, v)) = s.split_once("!=") {
+        return Ok(Requirement::NotIn(key(k)?, vec![v.trim().into()]));
+    }
+    if let Some((k, v)) = s.split_once("==").or_else(|| s.split_once('=')) {
+        return Ok(Requirement::In(key(k)?, vec![v.trim().into()]));
+    }
+    match s.strip_prefix('!') {
+        Some(k) => Ok(Requirement::NotExists(key(k)?)),
+        None => Ok(Requirement::Exists(key(s)?)),
+    }
+}
+
+/// Which Namespaces HAHAHA acts in, by name and by label selectors on the Namespace objects
+///
+/// A Namespace is included if no includes are given or it matches any of them, and excludes always win.
+#[derive(Clone, Debug, Default)]
+pub struct NamespaceFilter {
+    pub include: Vec<String>,
+    pub include_selector: Option<LabelSelector>,
+    pub exclude: Vec<String>,
+    pub exclude_selector: Option<LabelSelector>,
+}
+
+impl NamespaceFilter {
+    /// Whether Pods in a Namespace may be acted on
+    ///
+    /// `namespace` is the Namespace object, which is only needed for label selectors.
+    pub fn allows(&self, name: &str, namespace: Option<&Namespace>) -> bool {
+        let empty = BTreeMap::new();
+        let labels = namespace.and_then(|ns| ns.metadata.labels.as_ref()).unwrap_or(&empty);
+        if self.exclude.iter().any(|n| n == name) || self.exclude_selector.as_ref().is_some_and(|s| s.matches(labels)) {
+            return false;
+        }
+        if self.include.is_empty() && self.include_selector.is_none() {
+            return true;
+        }
+        self.include.iter().any(|n| n == name) || self.include_selector.as_ref().is_some_and(|s| s.matches(labels))
+    }
+
+    /// A field selector for Pods that enforces as much of the filter as the API server can
+    ///
+    /// Field selectors can't express either-or, so only excluded names and a single included name are used.
+    pub fn field_selector(&self) -> Option<String> {
+        let mut fields: Vec<String> = self
+            .exclude
+            .iter()
+            .map(|name| format!("metadata.namespace!={name}"))
+            .collect();
+        if let ([name], None) = (&self.include[..], &self.include_selector) {
+            fields.push(format!("metadata.namespace={name}"));
+        }
+        (!fields.is_empty()).then(|| fields.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kube::api::ObjectMeta;
+
+    fn labels(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn label_selectors_are_parsed_and_matched() {
+        let selector: LabelSelector = "team=a, env in (dev, test),!legacy,tier!=db,owner".parse().unwrap();
+        assert_eq!(selector.to_string(), "team=a,env in (dev,test),!legacy,tier!=db,owner");
+        assert!(selector.matches(&labels(&[("team", "a"), ("env", "dev"), ("owner", "x")])));
+        assert!(!selector.matches(&labels(&[("team", "a"), ("env", "prod"), ("owner", "x")])));
+        assert!(!selector.matches(&labels(&[
+            ("team", "a"),
+            ("env", "dev"),
+            ("owner", "x"),
+            ("legacy", "")
+        ])));
+        assert!(!selector.matches(&labels(&[
+            ("team", "a"),
+            ("env", "dev"),
+            ("owner", "x"),
+            ("tier", "db")
+        ])));
+        assert!(!selector.matches(&labels(&[("team", "a"), ("env", "dev")])));
+
+        for (selector, expected) in [
+            ("", "must not be empty"),
+            ("env in dev", "must be in parentheses"),
+            ("=a", "invalid label selector requirement"),
+        ] {
+            let err = selector.parse::<LabelSelector>().unwrap_err().to_string();
+            assert!(err.contains(expected), "expected `{}` in `{}`", expected, err);
+        }
+    }
+
+    #[test]
+    fn excludes_win_over_includes() {
+        let namespace = |name: &str, pairs: &[(&str, &str)]| Namespace {
+            metadata: ObjectMeta {
+                name: Some(name.into()),
+                labels: Some(labels(pairs)),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let filter = NamespaceFilter {
+            include: vec!["special".into()],
+            include_selector: Some("team".parse().unwrap()),
+            exclude: vec!["kube-system".into()],
+            exclude_selector: Some("hahaha=off".parse().unwrap()),
+        };
+        assert!(filter.allows("special", None));
+        assert!(filter.allows("a", Some(&namespace("a", &[("team", "a")]))));
+        assert!(!filter.allows("b", Some(&namespace("b", &[]))));
+        assert!(!filter.allows("kube-system", Some(&namespace("kube-system", &[("team", "platform")]))));
+        assert!(!filter.allows("c", Some(&namespace("c", &[("team", "c"), ("hahaha", "off")]))));
+        assert!(NamespaceFilter::default().allows("anything", None));
+
+        assert_eq!(
+            filter.field_selector().as_deref(),
+            Some("metadata.namespace!=kube-system")
+        );
+        let single = NamespaceFilter {
+            include: vec!["special".into()],
+            ..Default::default()
+        };
+        assert_eq!(single.field_selector().as_deref(), Some("metadata.namespace=special"));
+        assert_eq!(NamespaceFilter::default().field_selector(), None);
+    }
+}
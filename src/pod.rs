@@ -1,22 +1,207 @@
-use anyhow::{anyhow, Result};
-use k8s_openapi::api::core::v1::{ContainerStatus, Pod};
+use crate::actions::Action;
+use anyhow::{anyhow, Context, Result};
+use k8s_openapi::api::core::v1::{ContainerStateTerminated, ContainerStatus, Pod};
+use std::collections::BTreeSet;
+use std::{fmt, str::FromStr};
+
+/// Prefix of the annotations that override the action for a single container in a Pod
+pub const ACTION_ANNOTATION_PREFIX: &str = "hahaha.nais.io/action.";
+/// Annotation on a Pod or Namespace that opts in to deleting or evicting Pods whose sidecars won't shut down
+pub const LAST_RESORT_ANNOTATION: &str = "hahaha.nais.io/last-resort";
+/// Annotation on a Namespace that turns dry-run mode on or off for it, overriding the default
+pub const DRY_RUN_ANNOTATION: &str = "hahaha.nais.io/dry-run";
+/// Annotation on a Pod that overrides the `TerminationPolicy` for it
+pub const TERMINATION_POLICY_ANNOTATION: &str = "hahaha.nais.io/termination-policy";
+/// Annotation on a Pod that overrides how long to wait after the main container finished, e.g. `10s`
+pub const GRACE_PERIOD_ANNOTATION: &str = "hahaha.nais.io/grace-period";
+/// Annotation on a Pod or Namespace that makes HAHAHA leave its Pods alone when `true`
+pub const SKIP_ANNOTATION: &str = "hahaha.nais.io/skip";
+/// Annotation on a Pod naming sidecars that HAHAHA leaves alone, separated by `,`
+pub const SKIP_CONTAINERS_ANNOTATION: &str = "hahaha.nais.io/skip-containers";
+/// Annotation on a Pod naming its main containers, separated by `,`
+pub const MAIN_CONTAINERS_ANNOTATION: &str = "hahaha.nais.io/main-containers";
+/// Annotation on a Pod naming the container `kubectl` uses by default
+pub const DEFAULT_CONTAINER_ANNOTATION: &str = "kubectl.kubernetes.io/default-container";
+
+/// A way to find the main containers of a Pod, whose sidecars are shut down once they have terminated
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MainContainer {
+    /// The containers named in the `hahaha.nais.io/main-containers` annotation
+    Annotation,
+    /// The container named like the `app` label, as in NAIS jobs
+    AppLabel,
+    /// The container named in the `kubectl.kubernetes.io/default-container` annotation
+    DefaultContainer,
+    /// The first container in the Pod spec
+    First,
+    /// All containers that don't have a known shutdown action
+    WithoutAction,
+}
+
+impl fmt::Display for MainContainer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MainContainer::Annotation => f.write_str("annotation"),
+            MainContainer::AppLabel => f.write_str("app-label"),
+            MainContainer::DefaultContainer => f.write_str("default-container"),
+            MainContainer::First => f.write_str("first"),
+            MainContainer::WithoutAction => f.write_str("without-action"),
+        }
+    }
+}
+
+impl FromStr for MainContainer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "annotation" => Ok(MainContainer::Annotation),
+            "app-label" => Ok(MainContainer::AppLabel),
+            "default-container" => Ok(MainContainer::DefaultContainer),
+            "first" => Ok(MainContainer::First),
+            "without-action" => Ok(MainContainer::WithoutAction),
+            _ => Err(anyhow!(
+                "unknown main container resolution `{s}`, expected `annotation`, `app-label`, `default-container`, `first` or `without-action`"
+            )),
+        }
+    }
+}
+
+/// Ways to find the main containers of a Pod, tried in order until one of them finds any
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MainContainerResolution(pub Vec<MainContainer>);
+
+impl Default for MainContainerResolution {
+    fn default() -> Self {
+        Self(vec![MainContainer::Annotation, MainContainer::AppLabel])
+    }
+}
+
+impl fmt::Display for MainContainerResolution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<String> = self.0.iter().map(ToString::to_string).collect();
+        f.write_str(&names.join(","))
+    }
+}
+
+/// Parses a list separated by `,`, e.g. `annotation,app-label,first`
+impl FromStr for MainContainerResolution {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let resolution = s
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<_>>>()?;
+        if resolution.is_empty() {
+            return Err(anyhow!("at least one main container resolution is required"));
+        }
+        Ok(Self(resolution))
+    }
+}
+
+/// Which ways for the main application container to exit allow shutting down the sidecars
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TerminationPolicy {
+    /// Only when the main container exited with code 0
+    Success,
+    /// Whenever the main container has exited
+    #[default]
+    Any,
+    /// Whenever the main container has exited, unless it was `OOMKilled`, so that the Job controller can retry it
+    ExceptOomKilled,
+}
+
+impl TerminationPolicy {
+    /// Whether sidecars may be shut down after the main container terminated like this
+    pub fn allows(&self, terminated: &ContainerStateTerminated) -> bool {
+        match self {
+            TerminationPolicy::Success => terminated.exit_code == 0,
+            TerminationPolicy::Any => true,
+            TerminationPolicy::ExceptOomKilled => terminated.reason.as_deref() != Some("OOMKilled"),
+        }
+    }
+}
+
+impl fmt::Display for TerminationPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TerminationPolicy::Success => f.write_str("success"),
+            TerminationPolicy::Any => f.write_str("any"),
+            TerminationPolicy::ExceptOomKilled => f.write_str("except-oom-killed"),
+        }
+    }
+}
+
+impl FromStr for TerminationPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "success" => Ok(TerminationPolicy::Success),
+            "any" => Ok(TerminationPolicy::Any),
+            "except-oom-killed" => Ok(TerminationPolicy::ExceptOomKilled),
+            _ => Err(anyhow!(
+                "unknown termination policy `{s}`, expected `success`, `any` or `except-oom-killed`"
+            )),
+        }
+    }
+}
 
 /// Public extension trait for `Pod`
 pub trait Sidecars {
-    /// Get all `ContainerStatus`es except the main application container for a `Pod`
-    fn sidecars(&self) -> anyhow::Result<Vec<ContainerStatus>>;
-    /// Get the value of the `app` label in a Pod
-    fn job_name(&self) -> anyhow::Result<String>;
+    /// Find the names of the main containers in a Pod with the first way in `resolution` that finds any
+    ///
+    /// `has_action` tells whether a container has a known shutdown action, for `MainContainer::WithoutAction`.
+    fn main_containers(
+        &self,
+        resolution: &MainContainerResolution,
+        has_action: &dyn Fn(&ContainerStatus) -> bool,
+    ) -> Result<Vec<String>>;
+    /// Get all running `ContainerStatus`es except the `main` containers in a Pod, once the `main` containers have terminated
+    ///
+    /// Native sidecars are never included, since the kubelet stops them by itself.
+    fn sidecars(&self, main: &[String]) -> Vec<ContainerStatus>;
+    /// Get the names of the native sidecars in a Pod, i.e. init containers with `restartPolicy: Always`
+    fn native_sidecars(&self) -> Vec<String>;
+    /// Get the names of the regular containers in the spec of a Pod that aren't `main` containers
+    fn legacy_sidecars(&self, main: &[String]) -> Vec<String>;
+    /// Get the name of the job a Pod belongs to, for metrics
+    ///
+    /// This is the `app` label in NAIS jobs, the `job-name` label in other Jobs, or else the name of the Pod.
+    fn job_name(&self) -> String;
+    /// Get the action chain from the `hahaha.nais.io/action.<container>` annotation in a Pod, if any
+    ///
+    /// Actions in the chain are separated by `;`.
+    fn action_override(&self, container_name: &str) -> Option<anyhow::Result<Vec<Action>>>;
+    /// Get the names of the containers in the `hahaha.nais.io/skip-containers` annotation in a Pod
+    fn skipped_containers(&self) -> Vec<String>;
+    /// Whether all the `main` containers in a Pod have exited with code 0
+    fn main_containers_succeeded(&self, main: &[String]) -> bool;
+    /// How each of the `main` containers in a Pod terminated, by name, once all of them have
+    fn main_containers_terminated(&self, main: &[String]) -> Option<Vec<(String, ContainerStateTerminated)>>;
 }
 
 /// Extension trait for `Pod`
 ///
 /// Only used in the Sidecars trait
 trait SidecarStates {
-    /// Get all `ContainerStatus`es which are not terminated in a Pod
-    fn running_sidecars(&self) -> Result<Vec<ContainerStatus>>;
-    /// Get the `ContainerStatus` that matches the `app` label in a Pod
-    fn main_container(&self) -> Result<ContainerStatus>;
+    /// Get the `ContainerStatus`es of all containers in a Pod
+    fn container_statuses(&self) -> Vec<ContainerStatus>;
+    /// Get the names of all regular containers in the spec or status of a Pod
+    ///
+    /// Init containers are left out, including native sidecars.
+    fn container_names(&self) -> BTreeSet<String>;
+    /// Get the names of all init containers in the spec or status of a Pod
+    fn init_container_names(&self) -> BTreeSet<String>;
+    /// Find the names of the main containers in a Pod in one way
+    fn find_main_containers(
+        &self,
+        how: MainContainer,
+        has_action: &dyn Fn(&ContainerStatus) -> bool,
+    ) -> Result<Vec<String>>;
 }
 
 /// Extension trait for `ContainerStatus`
@@ -26,84 +211,210 @@ trait ContainerState {
 }
 
 impl Sidecars for Pod {
-    fn sidecars(&self) -> anyhow::Result<Vec<ContainerStatus>> {
-        let sidecars = self.running_sidecars()?;
+    fn main_containers(
+        &self,
+        resolution: &MainContainerResolution,
+        has_action: &dyn Fn(&ContainerStatus) -> bool,
+    ) -> Result<Vec<String>> {
+        let mut error = anyhow!("no main container resolution configured");
+        for how in &resolution.0 {
+            match self.find_main_containers(*how, has_action) {
+                Ok(names) if !names.is_empty() => return Ok(names),
+                Ok(_) => error = anyhow!("no main containers found by {how}"),
+                Err(err) => error = err,
+            }
+        }
+        Err(error)
+    }
+
+    fn sidecars(&self, main: &[String]) -> Vec<ContainerStatus> {
+        let init_containers = self.init_container_names();
+        let sidecars: Vec<ContainerStatus> = self
+            .container_statuses()
+            .into_iter()
+            .filter(|c| !main.contains(&c.name) && !init_containers.contains(&c.name) && !c.is_terminated())
+            .collect();
         if sidecars.is_empty() {
             // if there's nothing to be found, we're probably still starting up.
-            return Ok(sidecars);
+            return sidecars;
         }
-        let main_container = self.main_container()?;
-        if !main_container.is_terminated() {
-            return Ok(Vec::new());
+        // sidecars are only shut down once every main container has terminated
+        if self.main_containers_terminated(main).is_none() {
+            return Vec::new();
         }
-        Ok(sidecars)
+        sidecars
     }
 
-    fn job_name(&self) -> anyhow::Result<String> {
-        let Some(labels) = &self.metadata.labels else {
-            return Err(anyhow!("no labels found on pod"));
-        };
-        let Some(app_name) = labels.get("app") else {
-            return Err(anyhow!("no app name found on pod"));
-        };
-        Ok(app_name.into())
+    fn native_sidecars(&self) -> Vec<String> {
+        self.spec
+            .iter()
+            .flat_map(|spec| spec.init_containers.iter().flatten())
+            .filter(|c| c.restart_policy.as_deref() == Some("Always"))
+            .map(|c| c.name.clone())
+            .collect()
     }
-}
 
-impl SidecarStates for Pod {
-    fn running_sidecars(&self) -> Result<Vec<ContainerStatus>> {
-        let app = JobPod::from(self)?;
-        Ok(app
-            .statuses
+    fn legacy_sidecars(&self, main: &[String]) -> Vec<String> {
+        self.spec
             .iter()
-            .filter(|c| c.name != app.name && !c.is_terminated())
+            .flat_map(|spec| &spec.containers)
+            .filter(|c| !main.contains(&c.name))
+            .map(|c| c.name.clone())
+            .collect()
+    }
+
+    fn job_name(&self) -> String {
+        let labels = self.metadata.labels.as_ref();
+        labels
+            .and_then(|labels| labels.get("app").or_else(|| labels.get("job-name")))
             .cloned()
-            .collect())
+            .or_else(|| self.metadata.name.clone())
+            .unwrap_or_default()
+    }
+
+    fn action_override(&self, container_name: &str) -> Option<anyhow::Result<Vec<Action>>> {
+        let key = format!("{ACTION_ANNOTATION_PREFIX}{container_name}");
+        let value = self.metadata.annotations.as_ref()?.get(&key)?;
+        Some(
+            value
+                .split(';')
+                .map(|action| action.trim().parse())
+                .collect::<Result<Vec<Action>>>()
+                .with_context(|| format!("invalid annotation {key}")),
+        )
     }
 
-    fn main_container(&self) -> Result<ContainerStatus> {
-        let app = JobPod::from(self)?;
-        app.statuses
+    fn skipped_containers(&self) -> Vec<String> {
+        self.metadata
+            .annotations
             .iter()
-            .find(|c| c.name == app.name)
-            .map_or_else(|| Err(anyhow!("couldn't determine main containter")), |c| Ok(c.clone()))
+            .filter_map(|annotations| annotations.get(SKIP_CONTAINERS_ANNOTATION))
+            .flat_map(|names| names.split(','))
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(String::from)
+            .collect()
     }
-}
 
-struct JobPod {
-    pub name: String,
-    pub statuses: Vec<ContainerStatus>,
+    fn main_containers_succeeded(&self, main: &[String]) -> bool {
+        self.main_containers_terminated(main)
+            .is_some_and(|terminations| terminations.iter().all(|(_, terminated)| terminated.exit_code == 0))
+    }
+
+    fn main_containers_terminated(&self, main: &[String]) -> Option<Vec<(String, ContainerStateTerminated)>> {
+        let statuses = self.container_statuses();
+        main.iter()
+            .map(|name| {
+                let terminated = statuses
+                    .iter()
+                    .find(|c| &c.name == name)?
+                    .state
+                    .as_ref()?
+                    .terminated
+                    .clone()?;
+                Some((name.clone(), terminated))
+            })
+            .collect()
+    }
 }
 
-impl JobPod {
-    pub fn from(pod: &Pod) -> Result<Self> {
-        let app_name = &pod
-            .metadata
-            .labels
+impl SidecarStates for Pod {
+    fn container_statuses(&self) -> Vec<ContainerStatus> {
+        self.status
             .as_ref()
-            .ok_or_else(|| anyhow!("no labels found on pod"))?
-            .get("app")
-            .ok_or_else(|| anyhow!("no app name found on pod"))?;
+            .and_then(|status| status.container_statuses.clone())
+            .unwrap_or_default()
+    }
 
-        let container_statuses: Vec<ContainerStatus> = pod
+    fn container_names(&self) -> BTreeSet<String> {
+        let spec = self
+            .spec
+            .iter()
+            .flat_map(|spec| &spec.containers)
+            .map(|c| c.name.clone());
+        let status = self.container_statuses().into_iter().map(|c| c.name);
+        let init_containers = self.init_container_names();
+        spec.chain(status)
+            .filter(|name| !init_containers.contains(name))
+            .collect()
+    }
+
+    fn init_container_names(&self) -> BTreeSet<String> {
+        let spec = self
+            .spec
+            .iter()
+            .flat_map(|spec| spec.init_containers.iter().flatten())
+            .map(|c| c.name.clone());
+        let status = self
             .status
-            .as_ref()
-            .ok_or_else(|| anyhow!("no spec found on pod"))?
-            .container_statuses
-            .as_ref()
-            .map_or_else(Vec::new, Clone::clone);
+            .iter()
+            .flat_map(|status| status.init_container_statuses.iter().flatten())
+            .map(|c| c.name.clone());
+        spec.chain(status).collect()
+    }
 
-        Ok(Self {
-            name: (*app_name).to_string(),
-            statuses: container_statuses,
-        })
+    fn find_main_containers(
+        &self,
+        how: MainContainer,
+        has_action: &dyn Fn(&ContainerStatus) -> bool,
+    ) -> Result<Vec<String>> {
+        let annotation = |key: &str| {
+            self.metadata
+                .annotations
+                .as_ref()
+                .and_then(|annotations| annotations.get(key))
+                .ok_or_else(|| anyhow!("no {key} annotation found on pod"))
+        };
+        let names = match how {
+            MainContainer::Annotation => annotation(MAIN_CONTAINERS_ANNOTATION)?
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(String::from)
+                .collect(),
+            MainContainer::AppLabel => {
+                let app_name = self
+                    .metadata
+                    .labels
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("no labels found on pod"))?
+                    .get("app")
+                    .ok_or_else(|| anyhow!("no app name found on pod"))?;
+                vec![app_name.clone()]
+            }
+            MainContainer::DefaultContainer => vec![annotation(DEFAULT_CONTAINER_ANNOTATION)?.clone()],
+            MainContainer::First => self
+                .spec
+                .as_ref()
+                .and_then(|spec| spec.containers.first())
+                .map(|c| vec![c.name.clone()])
+                .ok_or_else(|| anyhow!("no containers found in pod spec"))?,
+            MainContainer::WithoutAction => self
+                .container_statuses()
+                .into_iter()
+                .filter(|c| !has_action(c))
+                .map(|c| c.name)
+                .collect(),
+        };
+        let known = self.container_names();
+        let unknown: Vec<&str> = names
+            .iter()
+            .filter(|name| !known.contains(*name))
+            .map(String::as_str)
+            .collect();
+        if !unknown.is_empty() {
+            return Err(anyhow!(
+                "couldn't determine main container: no container named {} found by {how}",
+                unknown.join(", ")
+            ));
+        }
+        Ok(names)
     }
 }
 
 impl ContainerState for ContainerStatus {
-    // We could probably see if the termination reason is
-    // a good one to avoid taking unnecessary measures.. but whatever
+    // The termination reason of the main container is checked against the `TerminationPolicy` in the reconciler
     fn is_terminated(&self) -> bool {
-        self.state.as_ref().map_or(false, |c| c.terminated.is_some())
+        self.state.as_ref().is_some_and(|c| c.terminated.is_some())
     }
 }
@@ -0,0 +1,129 @@
+use crate::actions::{Tls, UncheckedActionTable};
+use kube::CustomResource;
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{InstanceType, Schema, SchemaObject, StringValidation},
+    visit::{self, Visitor},
+    JsonSchema,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// Sidecar shutdown actions for the pods in a single namespace
+///
+/// These take precedence over both `ClusterSidecarShutdownPolicy` and the built-in actions.
+#[derive(CustomResource, Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[kube(
+    group = "hahaha.nais.io",
+    version = "v1alpha1",
+    kind = "SidecarShutdownPolicy",
+    shortname = "ssp",
+    namespaced
+)]
+pub struct SidecarShutdownPolicySpec {
+    #[serde(flatten)]
+    pub table: UncheckedActionTable,
+}
+
+/// Sidecar shutdown actions for the pods in all namespaces
+///
+/// These take precedence over the built-in actions.
+#[derive(CustomResource, Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[kube(
+    group = "hahaha.nais.io",
+    version = "v1alpha1",
+    kind = "ClusterSidecarShutdownPolicy",
+    shortname = "cssp"
+)]
+pub struct ClusterSidecarShutdownPolicySpec {
+    #[serde(flatten)]
+    pub table: UncheckedActionTable,
+}
+
+/// Schema for `T` that the Kubernetes API server accepts
+///
+/// `deny_unknown_fields` on `Action` and friends results in `additionalProperties: false` next to `properties`,
+/// which is not allowed in a structural schema.
+pub fn structural_schema<T: JsonSchema>(gen: &mut SchemaGenerator) -> Schema {
+    let mut schema = gen.subschema_for::<T>();
+    AllowAdditionalProperties.visit_schema(&mut schema);
+    schema
+}
+
+/// Schema for the `tls` of an HTTP action, with the checks that the API server can do itself
+pub fn tls_schema(gen: &mut SchemaGenerator) -> Schema {
+    let mut schema = gen.subschema_for::<Option<Tls>>().into_object();
+    schema.extensions.insert(
+        "x-kubernetes-validations".into(),
+        json!([{
+            "rule": "!(has(self.caFile) && has(self.insecureSkipVerify) && self.insecureSkipVerify)",
+            "message": "caFile can't be used with insecureSkipVerify",
+        }]),
+    );
+    schema.into()
+}
+
+/// Schema for a duration like `5s` or `1m 30s`, the way `humantime` parses it
+pub struct DurationString;
+
+impl JsonSchema for DurationString {
+    fn is_referenceable() -> bool {
+        false
+    }
+
+    fn schema_name() -> String {
+        "DurationString".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            instance_type: Some(InstanceType::String.into()),
+            string: Some(Box::new(StringValidation {
+                pattern: Some(r"^\s*([0-9]+\s*[a-zA-Zµ]+\s*)+$".into()),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+struct AllowAdditionalProperties;
+
+impl Visitor for AllowAdditionalProperties {
+    fn visit_schema_object(&mut self, schema: &mut SchemaObject) {
+        visit::visit_schema_object(self, schema);
+        if let Some(object) = &mut schema.object {
+            if !object.properties.is_empty() {
+                object.additional_properties = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kube::CustomResourceExt;
+
+    /// The CRDs in the chart are generated from the types above
+    ///
+    /// Run the tests with `UPDATE_CRDS=1` to regenerate them after changing the types.
+    #[test]
+    fn crds_in_chart_are_up_to_date() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/charts/templates/crds.yaml");
+        let crds = [SidecarShutdownPolicy::crd(), ClusterSidecarShutdownPolicy::crd()]
+            .iter()
+            .map(|crd| serde_yaml::to_string(crd).unwrap())
+            .collect::<Vec<_>>()
+            .join("---\n");
+        if std::env::var("UPDATE_CRDS").is_ok() {
+            std::fs::write(path, &crds).unwrap();
+        }
+        assert_eq!(
+            std::fs::read_to_string(path).unwrap(),
+            crds,
+            "CRDs in the chart are out of date, run the tests with UPDATE_CRDS=1"
+        );
+    }
+}
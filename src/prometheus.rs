@@ -1,14 +1,17 @@
 use futures::Future;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{server::Server, Body, Request, Response};
-use prometheus::{register_int_counter, register_int_counter_vec, Encoder, IntCounter, IntCounterVec, TextEncoder};
+use prometheus::{
+    register_int_counter, register_int_counter_vec, register_int_gauge, Encoder, IntCounter, IntCounterVec, IntGauge,
+    TextEncoder,
+};
 use tracing::{error, info};
 
 lazy_static! {
     pub static ref SIDECAR_SHUTDOWNS: IntCounterVec = register_int_counter_vec!(
         "hahaha_sidecar_shutdowns",
-        "Number of sidecar shutdowns",
-        &["container", "job_name", "namespace"],
+        "Number of sidecar shutdowns, by the kind of action that succeeded",
+        &["container", "job_name", "namespace", "strategy"],
     )
     .unwrap();
     pub static ref FAILED_SIDECAR_SHUTDOWNS: IntCounterVec = register_int_counter_vec!(
@@ -17,11 +20,58 @@ lazy_static! {
         &["container", "job_name", "namespace"],
     )
     .unwrap();
+    pub static ref SIDECAR_SHUTDOWN_UNVERIFIED: IntCounterVec = register_int_counter_vec!(
+        "hahaha_sidecar_shutdown_unverified",
+        "Number of sidecars still running after all their actions",
+        &["container", "job_name", "namespace"],
+    )
+    .unwrap();
+    pub static ref LAST_RESORT_REMOVALS: IntCounterVec = register_int_counter_vec!(
+        "hahaha_last_resort_removals",
+        "Number of pods deleted or evicted because their sidecars wouldn't shut down",
+        &["job_name", "namespace", "method"],
+    )
+    .unwrap();
+    pub static ref DRY_RUN_PLANNED_SHUTDOWNS: IntCounterVec = register_int_counter_vec!(
+        "hahaha_dry_run_planned_shutdowns",
+        "Number of sidecar shutdowns that would have been done outside of dry-run mode, by the kind of the first action",
+        &["container", "job_name", "namespace", "strategy"],
+    )
+    .unwrap();
+    pub static ref SKIPPED_PODS: IntCounterVec = register_int_counter_vec!(
+        "hahaha_skipped_pods",
+        "Number of pods that were left alone on purpose, by reason",
+        &["namespace", "reason"],
+    )
+    .unwrap();
+    pub static ref MIGRATED_PODS: IntCounterVec = register_int_counter_vec!(
+        "hahaha_migrated_pods",
+        "Number of pods with only native sidecars, which the kubelet stops by itself",
+        &["job_name", "namespace"],
+    )
+    .unwrap();
+    pub static ref MIXED_SIDECAR_PODS: IntCounterVec = register_int_counter_vec!(
+        "hahaha_mixed_sidecar_pods",
+        "Number of pods with both native sidecars and sidecars that HAHAHA shuts down",
+        &["job_name", "namespace"],
+    )
+    .unwrap();
     pub static ref TOTAL_UNSUCCESSFUL_EVENT_POSTS: IntCounter = register_int_counter!(
         "hahaha_total_unsuccessful_event_posts",
         "Total number of unsuccessful Kubernetes Event posts"
     )
     .unwrap();
+    pub static ref ACTION_RELOADS: IntCounterVec = register_int_counter_vec!(
+        "hahaha_action_reloads",
+        "Number of action file reloads, by result",
+        &["result"],
+    )
+    .unwrap();
+    pub static ref LEADER: IntGauge = register_int_gauge!(
+        "hahaha_leader",
+        "Whether this replica is the leader that acts on pods, which is always 1 without leader election"
+    )
+    .unwrap();
     pub static ref UNSUPPORTED_SIDECARS: IntCounterVec = register_int_counter_vec!(
         "hahaha_unsupported_sidecars",
         "Number of unsupported sidecars, by sidecar",
@@ -93,7 +143,7 @@ async fn server_functions_and_shuts_down_gracefully() {
     let amount = buffer
         .trim()
         .split(' ')
-        .last()
+        .next_back()
         .expect("Last word of trimmed message should be amount of unsuccessful events.");
     assert_eq!(
         amount,
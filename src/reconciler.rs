@@ -1,30 +1,224 @@
-use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use k8s_openapi::api::core::v1::Pod;
+use k8s_openapi::{
+    api::core::v1::{ContainerStatus, Namespace, Pod},
+    apimachinery::pkg::apis::meta::v1::Time,
+    chrono::Utc,
+};
 use kube::{
     runtime::{
         controller::Action as ReconcilerAction,
         events::{Event, EventType, Recorder, Reporter},
+        reflector::{ObjectRef, Store},
     },
     Api, Client, Resource, ResourceExt,
 };
 use thiserror::Error;
-use tracing::{debug, warn};
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+
+use crate::{
+    actions::{Action, ActionTable, Transport},
+    api::{Destroyer, LastResort, RetryLater},
+    backoff,
+    history::{History, PlannedRetry},
+    leader,
+    namespaces::NamespaceFilter,
+    pod::{
+        MainContainerResolution, Sidecars, TerminationPolicy, DRY_RUN_ANNOTATION, GRACE_PERIOD_ANNOTATION,
+        LAST_RESORT_ANNOTATION, SKIP_ANNOTATION, SKIP_CONTAINERS_ANNOTATION, TERMINATION_POLICY_ANNOTATION,
+    },
+    policy::{ClusterSidecarShutdownPolicy, SidecarShutdownPolicy},
+    prometheus::*,
+    watches::Watch,
+};
 
-use crate::{actions::Action, api::Destroyer, pod::Sidecars, prometheus::*};
+/// Delay before looking at a Pod again after its first failure
+static ERROR_BACKOFF: Duration = Duration::from_secs(5);
+static MAX_ERROR_BACKOFF: Duration = Duration::from_secs(5 * 60);
+/// Delay before looking for actions for sidecars that had none again, e.g. after a policy for them was created
+static UNSUPPORTED_RECHECK: Duration = Duration::from_secs(60);
+/// Delay before looking at a Pod again when its Namespace isn't known yet
+static NAMESPACE_WAIT: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     #[error("{0}: could not shut down sidecar {1}: {2}")]
     SidecarShutdownFailed(String, String, anyhow::Error),
     #[error("{0}: could not get running sidecars: {1}")]
     RunningSidecarError(String, anyhow::Error),
+    #[error("{0}: could not {1} pod: {2}")]
+    LastResortFailed(String, LastResort, anyhow::Error),
 }
 
 pub struct Data {
     pub(crate) client: Client,
     pub(crate) reporter: Reporter,
-    pub(crate) actions: BTreeMap<String, Action>,
+    /// The current actions, replaced whenever the action file is reloaded
+    pub(crate) actions: watch::Receiver<Arc<ActionTable>>,
+    pub(crate) policies: Store<SidecarShutdownPolicy>,
+    pub(crate) cluster_policies: Store<ClusterSidecarShutdownPolicy>,
+    pub(crate) history: History,
+    /// How long a sidecar may keep running after being shut down before escalating
+    pub(crate) verify_deadline: Duration,
+    pub(crate) namespaces: Store<Namespace>,
+    /// How many failures to allow before deleting or evicting a Pod that has opted in to it
+    pub(crate) last_resort_attempts: u32,
+    /// How long to try shutting down sidecars before deleting or evicting a Pod that has opted in to it
+    pub(crate) last_resort_budget: Duration,
+    /// How HTTP actions that don't choose a transport reach sidecars
+    pub(crate) http_transport: Transport,
+    /// Which ways for main containers to exit allow shutting down sidecars, unless a Pod overrides it
+    pub(crate) termination_policy: TerminationPolicy,
+    /// How long to wait after main containers finished before shutting down sidecars, unless a Pod overrides it
+    pub(crate) grace_period: Duration,
+    /// How to find the main containers of Pods
+    pub(crate) main_containers: MainContainerResolution,
+    /// Whether to only report what would be done instead of doing it, unless a Namespace overrides it
+    pub(crate) dry_run: bool,
+    /// Which Namespaces to act in
+    pub(crate) namespace_filter: NamespaceFilter,
+    /// The watches Pods come from, with the Pods each of them has
+    pub(crate) watches: Vec<(Watch, Store<Pod>)>,
+    /// Whether this replica is the leader, which is the only one that acts on Pods
+    pub(crate) leader: watch::Receiver<bool>,
+}
+
+impl Data {
+    /// Find the watch a Pod came from, which is the first one that has it if several watches do
+    fn watch_for(&self, pod: &Pod) -> Option<&Watch> {
+        let key = ObjectRef::from_obj(pod);
+        self.watches
+            .iter()
+            .find(|(_, pods)| pods.get(&key).is_some())
+            .map(|(watch, _)| watch)
+    }
+
+    /// Find out whether to only report what would be done to Pods in a Namespace instead of doing it
+    ///
+    /// The annotation on the Namespace wins over the default, and invalid values are ignored.
+    fn dry_run_for(&self, namespace: &Namespace) -> bool {
+        let Some(value) = namespace.annotations().get(DRY_RUN_ANNOTATION) else {
+            return self.dry_run;
+        };
+        value.parse().unwrap_or_else(|err| {
+            warn!("{}: ignoring {DRY_RUN_ANNOTATION}: {err}", namespace.name_any());
+            self.dry_run
+        })
+    }
+
+    /// Find out whether a Pod should be left alone, and whether that's decided by the `"pod"` or its `"namespace"`
+    ///
+    /// The annotation on the Pod wins over the one on its Namespace, and invalid values are ignored.
+    fn skip_for(&self, pod: &Pod, namespace: &Namespace) -> Option<&'static str> {
+        let (value, source) = match pod.annotations().get(SKIP_ANNOTATION) {
+            Some(value) => (value, "pod"),
+            None => (namespace.annotations().get(SKIP_ANNOTATION)?, "namespace"),
+        };
+        match value.parse() {
+            Ok(true) => Some(source),
+            Ok(false) => None,
+            Err(err) => {
+                warn!("{}: ignoring {SKIP_ANNOTATION}: {err}", pod.name_any());
+                None
+            }
+        }
+    }
+
+    /// Find out whether a Pod should be deleted or evicted if its sidecars won't shut down
+    ///
+    /// The annotation on the Pod wins over the one on its Namespace, and invalid values are ignored.
+    fn last_resort_for(&self, pod: &Pod, namespace: &str) -> Option<LastResort> {
+        let namespace = self.namespaces.get(&ObjectRef::new(namespace));
+        let value = pod.annotations().get(LAST_RESORT_ANNOTATION).or_else(|| {
+            namespace
+                .as_ref()
+                .and_then(|ns| ns.annotations().get(LAST_RESORT_ANNOTATION))
+        })?;
+        match value.parse() {
+            Ok(how) => Some(how),
+            Err(err) => {
+                warn!("{}: ignoring {LAST_RESORT_ANNOTATION}: {err}", pod.name_any());
+                None
+            }
+        }
+    }
+
+    /// Find out which ways for the main container of a Pod to exit allow shutting down its sidecars
+    ///
+    /// The annotation on the Pod wins over the default, and invalid values are ignored.
+    fn termination_policy_for(&self, pod: &Pod) -> TerminationPolicy {
+        let Some(value) = pod.annotations().get(TERMINATION_POLICY_ANNOTATION) else {
+            return self.termination_policy;
+        };
+        value.parse().unwrap_or_else(|err| {
+            warn!("{}: ignoring {TERMINATION_POLICY_ANNOTATION}: {err}", pod.name_any());
+            self.termination_policy
+        })
+    }
+
+    /// Find out how long to wait after the main container of a Pod finished before shutting down its sidecars
+    ///
+    /// The annotation on the Pod wins over the default, and invalid values are ignored.
+    fn grace_period_for(&self, pod: &Pod) -> Duration {
+        let Some(value) = pod.annotations().get(GRACE_PERIOD_ANNOTATION) else {
+            return self.grace_period;
+        };
+        humantime::parse_duration(value).unwrap_or_else(|err| {
+            warn!("{}: ignoring {GRACE_PERIOD_ANNOTATION}: {err}", pod.name_any());
+            self.grace_period
+        })
+    }
+
+    /// Find the action chain for a sidecar in a namespace
+    ///
+    /// `SidecarShutdownPolicy`s in the namespace win over `ClusterSidecarShutdownPolicy`s,
+    /// which in turn win over the action file and built-in actions.
+    /// The actions of the `watch` the Pod came from come next, before the action file and built-in actions.
+    /// Policies of the same kind are consulted in order of name, and invalid policies are skipped.
+    fn action_for(&self, namespace: &str, watch: Option<&Watch>, sidecar: &ContainerStatus) -> Option<Vec<Action>> {
+        let mut policies: Vec<_> = self
+            .policies
+            .state()
+            .into_iter()
+            .filter(|p| p.namespace().as_deref() == Some(namespace))
+            .collect();
+        policies.sort_by_key(|p| p.name_any());
+        let mut cluster_policies = self.cluster_policies.state();
+        cluster_policies.sort_by_key(|p| p.name_any());
+
+        let tables = policies
+            .iter()
+            .map(|p| {
+                (
+                    format!("SidecarShutdownPolicy {namespace}/{}", p.name_any()),
+                    &p.spec.table,
+                )
+            })
+            .chain(
+                cluster_policies
+                    .iter()
+                    .map(|p| (format!("ClusterSidecarShutdownPolicy {}", p.name_any()), &p.spec.table)),
+            );
+        for (policy, table) in tables {
+            // invalid policies are reported by the watch as they come in
+            let Ok(table) = table.parse() else {
+                debug!("{policy}: ignoring invalid policy for {}", sidecar.name);
+                continue;
+            };
+            if let Some(chain) = table.find(sidecar) {
+                return Some(chain.to_vec());
+            }
+        }
+        if let Some(chain) = watch.and_then(|watch| watch.actions.find(sidecar)) {
+            return Some(chain.to_vec());
+        }
+        self.actions.borrow().find(sidecar).map(<[Action]>::to_vec)
+    }
 }
 
 pub async fn reconcile(pod: Arc<Pod>, ctx: Arc<Data>) -> Result<ReconcilerAction, Error> {
@@ -34,113 +228,527 @@ pub async fn reconcile(pod: Arc<Pod>, ctx: Arc<Data>) -> Result<ReconcilerAction
 }
 
 pub async fn reconcile_inner(api: impl Destroyer, pod: Arc<Pod>, ctx: Arc<Data>) -> Result<ReconcilerAction, Error> {
+    // standby replicas only keep their caches warm, every pod is looked at again when they become the leader
+    if !*ctx.leader.borrow() {
+        debug!("{}: not the leader, leaving pod alone", pod.name_any());
+        return Ok(ReconcilerAction::await_change());
+    }
+
+    // another replica may take over once leadership is lost, so whatever is going on has to stop right away
+    tokio::select! {
+        res = act_on_pod(api, pod.clone(), ctx.clone()) => res,
+        () = leader::leadership_lost(ctx.leader.clone()) => {
+            warn!("{}: no longer the leader, leaving pod alone", pod.name_any());
+            Ok(ReconcilerAction::await_change())
+        }
+    }
+}
+
+async fn act_on_pod(api: impl Destroyer, pod: Arc<Pod>, ctx: Arc<Data>) -> Result<ReconcilerAction, Error> {
     let pod_name = pod.name_any();
     let namespace = match pod.namespace() {
         Some(namespace) => namespace,
         None => "default".into(),
     };
 
-    let running_sidecars = match pod.sidecars() {
-        Ok(sidecars) => sidecars,
-        Err(err) => return Err(Error::RunningSidecarError(pod_name, err)),
+    // the Namespace decides whether and how pods are handled, so nothing is done until it is known
+    let Some(namespace_object) = ctx.namespaces.get(&ObjectRef::new(&namespace)) else {
+        debug!("{pod_name}: waiting for namespace {namespace} to be known");
+        return Ok(ReconcilerAction::requeue(NAMESPACE_WAIT));
     };
 
-    if running_sidecars.is_empty() {
-        // There's no need to ever look at this pod again if there are no running sidecars
+    // pods in namespaces that aren't included are never touched, nor reported on
+    if !ctx.namespace_filter.allows(&namespace, Some(&namespace_object)) {
+        if ctx.history.first_report(&pod_key(&pod), "skipped") {
+            debug!("{pod_name}: skipping pod in namespace {namespace}");
+            SKIPPED_PODS.with_label_values(&[&namespace, "namespace"]).inc();
+        }
         return Ok(ReconcilerAction::await_change());
     }
 
     // set up a recorder for publishing events to the Pod
     let recorder = Recorder::new(ctx.client.clone(), ctx.reporter.clone(), pod.object_ref(&()));
 
+    // teams can ask to have pods left alone, which is reported once so that they know it was deliberate
+    if let Some(source) = ctx.skip_for(&pod, &namespace_object) {
+        if ctx.history.first_report(&pod_key(&pod), "skip-annotation") {
+            info!("{pod_name}: skipping pod because of {SKIP_ANNOTATION} on the {source}");
+            publish(
+                &recorder,
+                &pod_name,
+                Event {
+                    action: "Killing".into(),
+                    reason: "Skipped".into(),
+                    note: Some(format!(
+                        "Leaving sidecars alone because of the {SKIP_ANNOTATION} annotation on the {source}"
+                    )),
+                    type_: EventType::Normal,
+                    secondary: None,
+                },
+            )
+            .await;
+            SKIPPED_PODS.with_label_values(&[&namespace, "annotation"]).inc();
+        }
+        return Ok(ReconcilerAction::await_change());
+    }
+
+    let watch = ctx.watch_for(&pod);
+    let has_action =
+        |c: &ContainerStatus| pod.action_override(&c.name).is_some() || ctx.action_for(&namespace, watch, c).is_some();
+    let resolution = watch
+        .and_then(|watch| watch.main_containers.as_ref())
+        .unwrap_or(&ctx.main_containers);
+    let main_containers = match pod.main_containers(resolution, &has_action) {
+        Ok(names) => names,
+        Err(err) => return Err(Error::RunningSidecarError(pod_name, err)),
+    };
+    let mut running_sidecars = pod.sidecars(&main_containers);
+    let pod_key = pod_key(&pod);
+    let job_name = pod.job_name();
+
+    // sidecars that teams asked to have left alone are treated as if they had already stopped
+    let skipped_containers = pod.skipped_containers();
+    let skipped: Vec<String> = running_sidecars
+        .iter()
+        .filter(|c| skipped_containers.contains(&c.name))
+        .map(|c| c.name.clone())
+        .collect();
+    if !skipped.is_empty() {
+        running_sidecars.retain(|c| !skipped.contains(&c.name));
+        if ctx.history.first_report(&pod_key, "skip-containers") {
+            info!("{pod_name}: skipping containers {}", skipped.join(", "));
+            publish(
+                &recorder,
+                &pod_name,
+                Event {
+                    action: "Killing".into(),
+                    reason: "Skipped".into(),
+                    note: Some(format!(
+                        "Leaving containers {} alone because of the {SKIP_CONTAINERS_ANNOTATION} annotation",
+                        skipped.join(", ")
+                    )),
+                    type_: EventType::Normal,
+                    secondary: None,
+                },
+            )
+            .await;
+        }
+    }
+
+    // native sidecars are stopped by the kubelet, so they're only reported to keep track of the migration to them
+    let native_sidecars = pod.native_sidecars();
+    if !native_sidecars.is_empty() && ctx.history.first_report(&pod_key, "native-sidecars") {
+        let legacy_sidecars = pod.legacy_sidecars(&main_containers);
+        if legacy_sidecars.is_empty() {
+            debug!("{pod_name}: only has native sidecars: {}", native_sidecars.join(", "));
+            MIGRATED_PODS.with_label_values(&[&job_name, &namespace]).inc();
+        } else {
+            warn!(
+                "{pod_name}: has both native sidecars ({}) and other sidecars ({})",
+                native_sidecars.join(", "),
+                legacy_sidecars.join(", ")
+            );
+            publish(
+                &recorder,
+                &pod_name,
+                Event {
+                    action: "Killing".into(),
+                    reason: "MixedSidecars".into(),
+                    note: Some(format!(
+                        "Native sidecars {} are stopped by the kubelet, but sidecars {} are still shut down by HAHAHA",
+                        native_sidecars.join(", "),
+                        legacy_sidecars.join(", ")
+                    )),
+                    type_: EventType::Normal,
+                    secondary: None,
+                },
+            )
+            .await;
+            MIXED_SIDECAR_PODS.with_label_values(&[&job_name, &namespace]).inc();
+        }
+    }
+
+    if running_sidecars.is_empty() {
+        // There's no need to ever look at this pod again if there are no running sidecars
+        ctx.history.forget(&pod_key);
+        return Ok(ReconcilerAction::await_change());
+    }
+
     debug!("{pod_name}: needs help shutting down some residual containers");
 
-    let job_name = match pod.job_name() {
-        Ok(name) => name,
-        Err(e) => {
-            // this will never occur: running_sidecars will return on the same case
-            warn!("{pod_name}: could not find app label (will not retry): {e}");
+    if let Some(terminations) = pod.main_containers_terminated(&main_containers) {
+        let policy = ctx.termination_policy_for(&pod);
+        let allowed = terminations.iter().all(|(_, terminated)| policy.allows(terminated));
+        if ctx.history.mark_decided(&pod_key) {
+            let exits: Vec<String> = terminations
+                .iter()
+                .map(|(name, terminated)| match &terminated.reason {
+                    Some(reason) => format!("{name} exited with code {} ({reason})", terminated.exit_code),
+                    None => format!("{name} exited with code {}", terminated.exit_code),
+                })
+                .collect();
+            let exits = match exits.len() {
+                1 => format!("Main container {}", exits[0]),
+                _ => format!("Main containers {}", exits.join(" and ")),
+            };
+            let decision = if allowed {
+                "shutting down sidecars"
+            } else {
+                "leaving sidecars running"
+            };
+            debug!("{pod_name}: {exits}, {decision} (termination policy `{policy}`)");
+            publish(
+                &recorder,
+                &pod_name,
+                Event {
+                    action: "Killing".into(),
+                    reason: "TerminationPolicy".into(),
+                    note: Some(format!("{exits}, {decision} because of termination policy `{policy}`")),
+                    type_: EventType::Normal,
+                    secondary: None,
+                },
+            )
+            .await;
+        }
+        if !allowed {
+            return Ok(ReconcilerAction::await_change());
+        }
+
+        // give sidecars some time to flush whatever the main containers left them, by looking at the pod again later
+        let grace_period = ctx.grace_period_for(&pod);
+        let finished_at = terminations
+            .iter()
+            .filter_map(|(_, terminated)| terminated.finished_at.as_ref())
+            .max();
+        if let Some(Time(finished_at)) = finished_at {
+            let since = (Utc::now() - *finished_at).to_std().unwrap_or_default();
+            if since < grace_period {
+                let remaining = grace_period - since;
+                debug!(
+                    "{pod_name}: last main container finished {}s ago, shutting down sidecars in {}s",
+                    since.as_secs(),
+                    remaining.as_secs()
+                );
+                return Ok(ReconcilerAction::requeue(remaining));
+            }
+        }
+    }
+
+    let dry_run = ctx.dry_run_for(&namespace_object);
+    let last_resort = ctx.last_resort_for(&pod, &namespace).filter(|_| !dry_run);
+    if let (Some(how), Some(attempts)) = (last_resort, ctx.history.attempts(&pod_key)) {
+        let elapsed = attempts.started_at.elapsed();
+        let exhausted = attempts.failures >= ctx.last_resort_attempts || elapsed >= ctx.last_resort_budget;
+        if exhausted && pod.main_containers_succeeded(&main_containers) {
+            warn!(
+                "{pod_name}: sidecars still running after {} failures in {}s, resorting to {how}",
+                attempts.failures,
+                elapsed.as_secs()
+            );
+            if let Err(err) = api.remove(&pod_name, how).await {
+                return Err(Error::LastResortFailed(pod_name, how, err));
+            }
+            let sidecar_names: Vec<_> = running_sidecars.iter().map(|c| c.name.as_str()).collect();
+            publish(
+                &recorder,
+                &pod_name,
+                Event {
+                    action: "Killing".into(),
+                    reason: "LastResort".into(),
+                    note: Some(format!(
+                        "Used {how} on the pod after {} failures in {}s: sidecars {} would not shut down",
+                        attempts.failures,
+                        elapsed.as_secs(),
+                        sidecar_names.join(", ")
+                    )),
+                    type_: EventType::Warning,
+                    secondary: None,
+                },
+            )
+            .await;
+            LAST_RESORT_REMOVALS
+                .with_label_values(&[&job_name, &namespace, &how.to_string()])
+                .inc();
+            ctx.history.forget(&pod_key);
             return Ok(ReconcilerAction::await_change());
         }
+    }
+
+    // when to look at the pod again to verify that sidecars have terminated
+    let mut requeue_after: Option<Duration> = None;
+    let mut requeue_within = |duration: Duration| {
+        requeue_after = Some(requeue_after.map_or(duration, |d| d.min(duration)));
     };
 
     for sidecar in running_sidecars {
-        let sidecar_name = sidecar.name;
+        let sidecar_name = sidecar.name.clone();
         debug!("{pod_name}: found sidecar {sidecar_name}");
-        let Some(action) = ctx.actions.get(&sidecar_name) else {
-            warn!("{pod_name}: missing defined action: {sidecar_name}");
-            UNSUPPORTED_SIDECARS
-                .with_label_values(&[&sidecar_name, &job_name, &namespace])
-                .inc();
+        let actions = match pod.action_override(&sidecar_name) {
+            Some(Ok(actions)) => Some(actions),
+            Some(Err(err)) => {
+                if ctx.history.mark_invalid_annotation(&pod_key, &sidecar_name) {
+                    warn!("{pod_name}: {err:#}");
+                    publish(
+                        &recorder,
+                        &pod_name,
+                        Event {
+                            action: "Killing".into(),
+                            reason: "InvalidAnnotation".into(),
+                            note: Some(format!(
+                                "Ignoring action override for container {sidecar_name}: {err:#}"
+                            )),
+                            type_: EventType::Warning,
+                            secondary: None,
+                        },
+                    )
+                    .await;
+                }
+                ctx.action_for(&namespace, watch, &sidecar)
+            }
+            None => ctx.action_for(&namespace, watch, &sidecar),
+        };
+        let Some(actions) = actions else {
+            if ctx.history.mark_unsupported(&pod_key, &sidecar_name) {
+                warn!("{pod_name}: missing defined action: {sidecar_name}");
+                UNSUPPORTED_SIDECARS
+                    .with_label_values(&[&sidecar_name, &job_name, &namespace])
+                    .inc();
+            }
+            // nothing else triggers the pod when a policy with an action for the sidecar shows up
+            requeue_within(UNSUPPORTED_RECHECK);
             continue;
         };
+        let actions: Vec<Action> = actions
+            .into_iter()
+            .map(|action| action.or_transport(ctx.http_transport))
+            .collect();
 
-        let res = api.shutdown(action, &pod_name, &sidecar_name);
-        if let Err(err) = res.await {
-            if let Err(e) = recorder
-                .publish(Event {
-                    action: "Killing".into(),
-                    reason: "Killing".into(),
-                    note: Some(format!("Unsuccessfully shut down container {sidecar_name}: {err}")),
-                    type_: EventType::Warning,
-                    secondary: None,
-                })
-                .await
-            {
-                warn!("{pod_name}: couldn't publish Kubernetes Event: {e}");
-                TOTAL_UNSUCCESSFUL_EVENT_POSTS.inc();
+        if dry_run {
+            if ctx.history.mark_planned(&pod_key, &sidecar_name) {
+                let chain: Vec<String> = actions.iter().map(ToString::to_string).collect();
+                let chain = chain.join(", then ");
+                info!("{pod_name}: dry run: would shut down {sidecar_name} via {chain}");
+                publish(
+                    &recorder,
+                    &pod_name,
+                    Event {
+                        action: "Killing".into(),
+                        reason: "DryRun".into(),
+                        note: Some(format!("Would shut down container {sidecar_name} via {chain}")),
+                        type_: EventType::Normal,
+                        secondary: None,
+                    },
+                )
+                .await;
+                DRY_RUN_PLANNED_SHUTDOWNS
+                    .with_label_values(&[&sidecar_name, &job_name, &namespace, actions[0].kind()])
+                    .inc();
             }
-            FAILED_SIDECAR_SHUTDOWNS
-                .with_label_values(&[&sidecar_name, &job_name, &namespace])
-                .inc();
-            return Err(Error::SidecarShutdownFailed(pod_name, sidecar_name, err));
+            continue;
+        }
+
+        let retry = ctx.history.retry(&pod_key, &sidecar_name);
+        if let Some(retry) = retry {
+            let left = retry.at.saturating_duration_since(Instant::now());
+            if !left.is_zero() {
+                debug!("{pod_name}: waiting to retry shutting down {sidecar_name}");
+                requeue_within(left);
+                continue;
+            }
+        }
+        let (first_action, retried) = match (retry, ctx.history.sidecar(&pod_key, &sidecar_name)) {
+            (Some(retry), _) => (retry.action, retry.retried),
+            (None, None) => (0, 0),
+            (None, Some(history)) => {
+                let waited = history.acted_at.elapsed();
+                if waited < ctx.verify_deadline {
+                    debug!("{pod_name}: waiting for {sidecar_name} to terminate");
+                    requeue_within(ctx.verify_deadline - waited);
+                    continue;
+                }
+                if history.next_action >= actions.len() {
+                    if ctx.history.mark_unverified(&pod_key, &sidecar_name) {
+                        warn!("{pod_name}: {sidecar_name} is still running after all actions, giving up");
+                        publish(
+                            &recorder,
+                            &pod_name,
+                            Event {
+                                action: "Killing".into(),
+                                reason: "ShutdownUnverified".into(),
+                                note: Some(format!(
+                                    "Container {sidecar_name} is still running {}s after being shut down",
+                                    waited.as_secs()
+                                )),
+                                type_: EventType::Warning,
+                                secondary: None,
+                            },
+                        )
+                        .await;
+                        SIDECAR_SHUTDOWN_UNVERIFIED
+                            .with_label_values(&[&sidecar_name, &job_name, &namespace])
+                            .inc();
+                        ctx.history.record_failure(&pod_key);
+                    }
+                    if let (Some(_), Some(attempts)) = (last_resort, ctx.history.attempts(&pod_key)) {
+                        if pod.main_containers_succeeded(&main_containers) {
+                            // come back to delete or evict the pod once the budget runs out
+                            requeue_within(ctx.last_resort_budget.saturating_sub(attempts.started_at.elapsed()));
+                        }
+                    }
+                    continue;
+                }
+                warn!(
+                    "{pod_name}: {sidecar_name} is still running {}s after being shut down, escalating",
+                    waited.as_secs()
+                );
+                (history.next_action, 0)
+            }
+        };
+
+        let res = api
+            .shutdown(&actions[first_action..], retried, &pod_name, &sidecar_name)
+            .await;
+        // retries wait outside of the reconcile, so that it doesn't hold on to the pod in the meantime
+        let retry = res.as_ref().err().and_then(|err| err.downcast_ref::<RetryLater>());
+        ctx.history.plan_retry(
+            &pod_key,
+            &sidecar_name,
+            retry.map(|retry| PlannedRetry {
+                action: first_action + retry.action,
+                retried: retry.retried,
+                at: Instant::now() + retry.delay,
+            }),
+        );
+        if let Some(retry) = retry {
+            requeue_within(retry.delay);
+            continue;
         }
-        if let Err(e) = recorder
-            .publish(Event {
+        let (index, action, output) = match res {
+            Ok(shutdown) => {
+                let index = first_action + shutdown.action;
+                (index, &actions[index], shutdown.output)
+            }
+            Err(err) => {
+                publish(
+                    &recorder,
+                    &pod_name,
+                    Event {
+                        action: "Killing".into(),
+                        reason: "Killing".into(),
+                        note: Some(format!("Unsuccessfully shut down container {sidecar_name}: {err}")),
+                        type_: EventType::Warning,
+                        secondary: None,
+                    },
+                )
+                .await;
+                FAILED_SIDECAR_SHUTDOWNS
+                    .with_label_values(&[&sidecar_name, &job_name, &namespace])
+                    .inc();
+                ctx.history.record_failure(&pod_key);
+                return Err(Error::SidecarShutdownFailed(pod_name, sidecar_name, err));
+            }
+        };
+        publish(
+            &recorder,
+            &pod_name,
+            Event {
                 action: "Killing".into(),
                 reason: "Killing".into(),
-                note: Some(format!("Shut down container {sidecar_name}")),
+                note: Some(match output {
+                    Some(output) => format!("Shut down container {sidecar_name} with {action}: {output}"),
+                    None => format!("Shut down container {sidecar_name} with {action}"),
+                }),
                 type_: EventType::Normal,
                 secondary: None,
-            })
-            .await
-        {
-            warn!("{pod_name}: couldn't publish Kubernetes Event: {e}");
-            TOTAL_UNSUCCESSFUL_EVENT_POSTS.inc();
-        }
+            },
+        )
+        .await;
         SIDECAR_SHUTDOWNS
-            .with_label_values(&[&sidecar_name, &job_name, &namespace])
+            .with_label_values(&[&sidecar_name, &job_name, &namespace, action.kind()])
             .inc();
+        ctx.history.record_shutdown(&pod_key, &sidecar_name, index + 1);
+        requeue_within(ctx.verify_deadline);
     }
 
-    Ok(ReconcilerAction::await_change())
+    Ok(requeue_after.map_or_else(ReconcilerAction::await_change, ReconcilerAction::requeue))
+}
+
+/// Publish a Kubernetes Event to the Pod, logging and counting failures instead of returning them
+async fn publish(recorder: &Recorder, pod_name: &str, event: Event) {
+    if let Err(e) = recorder.publish(event).await {
+        warn!("{pod_name}: couldn't publish Kubernetes Event: {e}");
+        TOTAL_UNSUCCESSFUL_EVENT_POSTS.inc();
+    }
+}
+
+/// Look at a Pod again after a failure, backing off exponentially with the number of failures for it
+pub fn error_policy(pod: Arc<Pod>, _error: &Error, ctx: Arc<Data>) -> ReconcilerAction {
+    ReconcilerAction::requeue(error_backoff(&ctx.history, &pod))
 }
 
-pub fn error_policy(_pod: Arc<Pod>, _error: &Error, _ctx: Arc<Data>) -> ReconcilerAction {
-    ReconcilerAction::requeue(Duration::from_secs(30))
+fn error_backoff(history: &History, pod: &Pod) -> Duration {
+    let failures = history.attempts(&pod_key(pod)).map_or(0, |a| a.failures);
+    backoff::jittered(ERROR_BACKOFF, MAX_ERROR_BACKOFF, failures.saturating_sub(1))
+}
+
+/// Identifies a Pod in the `History`
+fn pod_key(pod: &Pod) -> String {
+    pod.uid()
+        .unwrap_or_else(|| format!("{}/{}", pod.namespace().unwrap_or_default(), pod.name_any()))
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{collections::BTreeMap, sync::Arc};
+    use std::{collections::BTreeMap, sync::Arc, time::Duration};
 
     use crate::{
-        api::MockDestroyer,
-        reconciler::{reconcile_inner, Data},
+        actions::{Action, ActionTable, HttpRequest, Transport, UncheckedActionTable},
+        api::{Destroyer, LastResort, MockDestroyer, RetryLater, Shutdown},
+        history::History,
+        namespaces::NamespaceFilter,
+        pod::{MainContainer, MainContainerResolution, TerminationPolicy},
+        policy::{
+            ClusterSidecarShutdownPolicy, ClusterSidecarShutdownPolicySpec, SidecarShutdownPolicy,
+            SidecarShutdownPolicySpec,
+        },
+        prometheus::{
+            DRY_RUN_PLANNED_SHUTDOWNS, LAST_RESORT_REMOVALS, MIGRATED_PODS, MIXED_SIDECAR_PODS, SIDECAR_SHUTDOWNS,
+            SIDECAR_SHUTDOWN_UNVERIFIED, SKIPPED_PODS, UNSUPPORTED_SIDECARS,
+        },
+        reconciler::{error_backoff, pod_key, reconcile_inner, Data, NAMESPACE_WAIT, UNSUPPORTED_RECHECK},
+        watches::Watch,
     };
     use hyper::Uri;
     use k8s_openapi::{
         api::core::v1::{
-            ContainerState, ContainerStateRunning, ContainerStateTerminated, ContainerStatus, Pod, PodStatus,
+            Container, ContainerState, ContainerStateRunning, ContainerStateTerminated, ContainerStatus, Namespace,
+            Pod, PodSpec, PodStatus,
         },
         apimachinery::pkg::apis::meta::v1::Time,
-        chrono::Utc,
+        chrono::{self, Utc},
     };
-    use kube::{api::ObjectMeta, client::ConfigExt, runtime::events::Reporter, Client, Config};
+    use kube::{
+        api::ObjectMeta,
+        client::ConfigExt,
+        runtime::{
+            controller::Action as ReconcilerAction,
+            events::Reporter,
+            reflector::{store::Writer, Store},
+            watcher,
+        },
+        Client, Config,
+    };
+    use serde_json::json;
+    use tokio::sync::watch;
     use tower::ServiceBuilder;
 
-    /// creates a bogus kube client that doesn't connect anywhere useful
+    /// creates a bogus kube client that doesn't connect anywhere useful, and only knows the `default` namespace
     fn make_data() -> Data {
+        make_data_with(&[ns("default")])
+    }
+
+    /// like `make_data`, but knows exactly `namespaces`
+    fn make_data_with(namespaces: &[Namespace]) -> Data {
         let config = Config::new("/".parse::<Uri>().unwrap());
         let service = ServiceBuilder::new()
             .layer(config.base_uri_layer())
@@ -148,7 +756,22 @@ mod tests {
             .service(hyper::Client::new());
 
         Data {
-            actions: crate::actions::generate(),
+            actions: watch::channel(Arc::new(crate::actions::defaults())).1,
+            policies: store(vec![]),
+            cluster_policies: store(vec![]),
+            history: History::default(),
+            verify_deadline: Duration::ZERO,
+            namespaces: store(namespaces.to_vec()),
+            last_resort_attempts: 1,
+            last_resort_budget: Duration::from_secs(3600),
+            http_transport: Transport::Portforward,
+            termination_policy: TerminationPolicy::Any,
+            grace_period: Duration::ZERO,
+            main_containers: MainContainerResolution::default(),
+            dry_run: false,
+            namespace_filter: NamespaceFilter::default(),
+            watches: vec![],
+            leader: watch::channel(true).1,
             client: Client::new(service, config.default_namespace),
             reporter: Reporter {
                 controller: "hahaha".into(),
@@ -157,6 +780,30 @@ mod tests {
         }
     }
 
+    /// a `Store` with `objects` in it
+    fn store<K>(objects: Vec<K>) -> Store<K>
+    where
+        K: kube::Resource + Clone + 'static,
+        K::DynamicType: Default + Eq + std::hash::Hash + Clone,
+    {
+        let mut writer = Writer::default();
+        for object in objects {
+            writer.apply_watcher_event(&watcher::Event::Applied(object));
+        }
+        writer.as_reader()
+    }
+
+    /// a Namespace without labels or annotations
+    fn ns(name: &str) -> Namespace {
+        Namespace {
+            metadata: ObjectMeta {
+                name: Some(name.into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
     fn make_pod(
         name: String,
         labels: Option<BTreeMap<String, String>>,
@@ -192,7 +839,10 @@ mod tests {
     #[tokio::test]
     async fn reconcile_ok_on_successful_shutdown() {
         let mut destroyer = MockDestroyer::new();
-        destroyer.expect_shutdown().times(1).returning(|_, _, _| Ok(()));
+        destroyer
+            .expect_shutdown()
+            .times(1)
+            .returning(|_, _, _, _| Ok(Shutdown::default()));
 
         let name: String = String::from("oh-no");
 
@@ -221,7 +871,10 @@ mod tests {
     #[tokio::test]
     async fn reconcile_ok_on_no_running_sidecars() {
         let mut destroyer = MockDestroyer::new();
-        destroyer.expect_shutdown().times(0).returning(|_, _, _| Ok(()));
+        destroyer
+            .expect_shutdown()
+            .times(0)
+            .returning(|_, _, _, _| Ok(Shutdown::default()));
 
         let name: String = String::from("oh-no");
 
@@ -243,7 +896,7 @@ mod tests {
         destroyer
             .expect_shutdown()
             .times(1)
-            .returning(|_, _, _| Err(anyhow::anyhow!("couldn't shutdown!")));
+            .returning(|_, _, _, _| Err(anyhow::anyhow!("couldn't shutdown!")));
         let name = String::from("oh-no");
 
         let labels: BTreeMap<String, String> = BTreeMap::from([("app".into(), name.clone())]);
@@ -271,10 +924,1130 @@ mod tests {
         );
     }
 
+    fn running_container(name: &str) -> ContainerStatus {
+        ContainerStatus {
+            name: name.into(),
+            state: Some(ContainerState {
+                running: Some(ContainerStateRunning {
+                    started_at: Some(Time(Utc::now())),
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn exec_action(command: &str) -> UncheckedActionTable {
+        UncheckedActionTable {
+            actions: BTreeMap::from([("cloudsql-proxy".into(), json!([{"exec": {"command": [command]}}]))]),
+            rules: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn reconcile_prefers_namespace_policy_over_cluster_policy_and_builtin() {
+        let mut destroyer = MockDestroyer::new();
+        destroyer
+            .expect_shutdown()
+            .times(1)
+            .withf(|actions, _, _, _| matches!(actions, [Action::Exec { command, .. }] if command == &["namespaced"]))
+            .returning(|_, _, _, _| Ok(Shutdown::default()));
+
+        let name: String = String::from("oh-no");
+        let labels: BTreeMap<String, String> = BTreeMap::from([("app".into(), name.clone())]);
+        let mut pod = make_pod(name, Some(labels), vec![running_container("cloudsql-proxy")]);
+        pod.metadata.namespace = Some("team".into());
+
+        let mut policy = SidecarShutdownPolicy::new(
+            "policy",
+            SidecarShutdownPolicySpec {
+                table: exec_action("namespaced"),
+            },
+        );
+        policy.metadata.namespace = Some("team".into());
+        let mut other_namespace_policy = SidecarShutdownPolicy::new(
+            "policy",
+            SidecarShutdownPolicySpec {
+                table: exec_action("other-namespace"),
+            },
+        );
+        other_namespace_policy.metadata.namespace = Some("other-team".into());
+        let cluster_policy = ClusterSidecarShutdownPolicy::new(
+            "policy",
+            ClusterSidecarShutdownPolicySpec {
+                table: exec_action("cluster"),
+            },
+        );
+
+        let mut data = make_data_with(&[ns("team")]);
+        data.policies = store(vec![other_namespace_policy, policy]);
+        data.cluster_policies = store(vec![cluster_policy]);
+
+        let ret = reconcile_inner(destroyer, Arc::new(pod), Arc::new(data)).await;
+
+        assert!(ret.is_ok());
+    }
+
+    #[tokio::test]
+    async fn reconcile_skips_policies_it_cannot_parse() {
+        let mut destroyer = MockDestroyer::new();
+        destroyer
+            .expect_shutdown()
+            .times(1)
+            .withf(|actions, _, _, _| matches!(actions, [Action::Exec { command, .. }] if command == &["cluster"]))
+            .returning(|_, _, _, _| Ok(Shutdown::default()));
+
+        let name: String = String::from("oh-no");
+        let labels: BTreeMap<String, String> = BTreeMap::from([("app".into(), name.clone())]);
+        let mut pod = make_pod(name, Some(labels), vec![running_container("cloudsql-proxy")]);
+        pod.metadata.namespace = Some("team".into());
+
+        // the API server may store policies that HAHAHA can't make sense of, which mustn't break the watch
+        let broken: Vec<SidecarShutdownPolicy> = vec![
+            json!({"cloudsql-proxy": [{"exec": {"command": ["broken"], "timeout": "5"}}]}),
+            json!({"cloudsql-proxy": [{"portforward": {"method": "POST", "path": "/quit", "port": 70000}}]}),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, actions)| {
+            serde_json::from_value(json!({
+                "apiVersion": "hahaha.nais.io/v1alpha1",
+                "kind": "SidecarShutdownPolicy",
+                "metadata": {"name": format!("broken-{i}"), "namespace": "team"},
+                "spec": {"actions": actions, "rules": [{"match": {"nameRegex": "("}, "actions": []}]},
+            }))
+            .unwrap()
+        })
+        .collect();
+        let cluster_policy = ClusterSidecarShutdownPolicy::new(
+            "policy",
+            ClusterSidecarShutdownPolicySpec {
+                table: exec_action("cluster"),
+            },
+        );
+
+        let mut data = make_data_with(&[ns("team")]);
+        data.policies = store(broken);
+        data.cluster_policies = store(vec![cluster_policy]);
+
+        let ret = reconcile_inner(destroyer, Arc::new(pod), Arc::new(data)).await;
+
+        assert!(ret.is_ok());
+    }
+
+    #[tokio::test]
+    async fn reconcile_prefers_action_annotation() {
+        let mut destroyer = MockDestroyer::new();
+        destroyer
+            .expect_shutdown()
+            .times(1)
+            .withf(|actions, _, _, _| {
+                matches!(
+                    actions,
+                    [Action::Portforward(HttpRequest { port: 8080, .. }), Action::Exec { .. }]
+                )
+            })
+            .returning(|_, _, _, _| Ok(Shutdown::default()));
+
+        let name: String = String::from("oh-no");
+        let labels: BTreeMap<String, String> = BTreeMap::from([("app".into(), name.clone())]);
+        let mut pod = make_pod(name, Some(labels), vec![running_container("cloudsql-proxy")]);
+        pod.metadata.annotations = Some(BTreeMap::from([(
+            "hahaha.nais.io/action.cloudsql-proxy".into(),
+            "portforward:POST:/quit:8080; exec:/bin/kill -s KILL 1".into(),
+        )]));
+
+        let ret = reconcile_inner(destroyer, Arc::new(pod), Arc::new(make_data())).await;
+
+        assert!(ret.is_ok());
+    }
+
+    #[tokio::test]
+    async fn reconcile_records_which_action_in_chain_succeeded() {
+        let mut destroyer = MockDestroyer::new();
+        destroyer.expect_shutdown().times(1).returning(|_, _, _, _| {
+            Ok(Shutdown {
+                action: 1,
+                ..Default::default()
+            })
+        });
+
+        let name: String = String::from("fallback");
+        let labels: BTreeMap<String, String> = BTreeMap::from([("app".into(), name.clone())]);
+        let mut pod = make_pod(name, Some(labels), vec![running_container("cloudsql-proxy")]);
+        pod.metadata.annotations = Some(BTreeMap::from([(
+            "hahaha.nais.io/action.cloudsql-proxy".into(),
+            "portforward:POST:/quit:8080;exec:/bin/kill -s TERM 1".into(),
+        )]));
+
+        let ret = reconcile_inner(destroyer, Arc::new(pod), Arc::new(make_data())).await;
+
+        assert!(ret.is_ok());
+        let shutdowns = |strategy| {
+            SIDECAR_SHUTDOWNS
+                .with_label_values(&["cloudsql-proxy", "fallback", "default", strategy])
+                .get()
+        };
+        assert_eq!(shutdowns("exec"), 1);
+        assert_eq!(shutdowns("portforward"), 0);
+    }
+
+    #[tokio::test]
+    async fn reconcile_retries_actions_after_their_backoff() {
+        let name: String = String::from("retrying");
+        let labels: BTreeMap<String, String> = BTreeMap::from([("app".into(), name.clone())]);
+        let mut pod = make_pod(name, Some(labels), vec![running_container("cloudsql-proxy")]);
+        pod.metadata.annotations = Some(BTreeMap::from([(
+            "hahaha.nais.io/action.cloudsql-proxy".into(),
+            "portforward:POST:/quit:8080;exec:/bin/kill -s TERM 1".into(),
+        )]));
+        let pod = Arc::new(pod);
+        let data = Arc::new(make_data());
+        let delay = Duration::from_millis(50);
+
+        // the second action fails, and the reconcile returns instead of waiting to retry it
+        let mut destroyer = MockDestroyer::new();
+        destroyer
+            .expect_shutdown()
+            .times(1)
+            .withf(|actions, retried, _, _| actions.len() == 2 && *retried == 0)
+            .returning(move |_, _, _, _| {
+                Err(RetryLater {
+                    action: 1,
+                    retried: 1,
+                    delay,
+                    error: anyhow::anyhow!("nope"),
+                }
+                .into())
+            });
+        let ret = reconcile_inner(destroyer, pod.clone(), data.clone()).await;
+        assert_eq!(ret.unwrap(), ReconcilerAction::requeue(delay));
+
+        // nothing is done before the retry is due
+        let mut destroyer = MockDestroyer::new();
+        destroyer.expect_shutdown().times(0);
+        let ret = reconcile_inner(destroyer, pod.clone(), data.clone()).await;
+        assert!(ret.is_ok());
+
+        // the retry picks up where the chain left off, and isn't counted as a failure
+        tokio::time::sleep(delay).await;
+        let mut destroyer = MockDestroyer::new();
+        destroyer
+            .expect_shutdown()
+            .times(1)
+            .withf(|actions, retried, _, _| matches!(actions, [Action::Exec { .. }]) && *retried == 1)
+            .returning(|_, _, _, _| Ok(Shutdown::default()));
+        let ret = reconcile_inner(destroyer, pod.clone(), data.clone()).await;
+        assert!(ret.is_ok());
+        assert_eq!(data.history.attempts(&pod_key(&pod)).unwrap().failures, 0);
+        assert_eq!(
+            data.history
+                .sidecar(&pod_key(&pod), "cloudsql-proxy")
+                .unwrap()
+                .next_action,
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn reconcile_escalates_when_sidecar_keeps_running() {
+        let name: String = String::from("stubborn");
+        let labels: BTreeMap<String, String> = BTreeMap::from([("app".into(), name.clone())]);
+        let mut pod = make_pod(name, Some(labels), vec![running_container("cloudsql-proxy")]);
+        pod.metadata.annotations = Some(BTreeMap::from([(
+            "hahaha.nais.io/action.cloudsql-proxy".into(),
+            "portforward:POST:/quit:8080;exec:/bin/kill -s TERM 1".into(),
+        )]));
+        let pod = Arc::new(pod);
+        let data = Arc::new(make_data());
+
+        let mut destroyer = MockDestroyer::new();
+        destroyer
+            .expect_shutdown()
+            .times(1)
+            .withf(|actions, _, _, _| actions.len() == 2)
+            .returning(|_, _, _, _| Ok(Shutdown::default()));
+        let ret = reconcile_inner(destroyer, pod.clone(), data.clone()).await;
+        assert_eq!(ret.unwrap(), ReconcilerAction::requeue(Duration::ZERO));
+
+        let mut destroyer = MockDestroyer::new();
+        destroyer
+            .expect_shutdown()
+            .times(1)
+            .withf(|actions, _, _, _| matches!(actions, [Action::Exec { .. }]))
+            .returning(|_, _, _, _| Ok(Shutdown::default()));
+        let ret = reconcile_inner(destroyer, pod.clone(), data.clone()).await;
+        assert_eq!(ret.unwrap(), ReconcilerAction::requeue(Duration::ZERO));
+
+        let unverified = || {
+            SIDECAR_SHUTDOWN_UNVERIFIED
+                .with_label_values(&["cloudsql-proxy", "stubborn", "default"])
+                .get()
+        };
+        for _ in 0..2 {
+            let mut destroyer = MockDestroyer::new();
+            destroyer.expect_shutdown().times(0);
+            let ret = reconcile_inner(destroyer, pod.clone(), data.clone()).await;
+            assert_eq!(ret.unwrap(), ReconcilerAction::await_change());
+            assert_eq!(unverified(), 1);
+        }
+    }
+
+    fn stuck_pod(name: &str, main_exit_code: i32) -> Pod {
+        let labels: BTreeMap<String, String> = BTreeMap::from([("app".into(), name.into())]);
+        let mut pod = make_pod(name.into(), Some(labels), vec![running_container("cloudsql-proxy")]);
+        pod.metadata.namespace = Some("stuck".into());
+        pod.status.as_mut().unwrap().container_statuses.as_mut().unwrap()[0]
+            .state
+            .as_mut()
+            .unwrap()
+            .terminated
+            .as_mut()
+            .unwrap()
+            .exit_code = main_exit_code;
+        pod
+    }
+
+    fn last_resort_namespace(how: &str) -> Namespace {
+        Namespace {
+            metadata: ObjectMeta {
+                name: Some("stuck".into()),
+                annotations: Some(BTreeMap::from([("hahaha.nais.io/last-resort".into(), how.into())])),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn reconcile_removes_pod_as_last_resort_after_failed_attempts() {
+        let pod = Arc::new(stuck_pod("last-resort", 0));
+        let data = Arc::new(make_data_with(&[last_resort_namespace("evict")]));
+
+        let mut destroyer = MockDestroyer::new();
+        destroyer
+            .expect_shutdown()
+            .times(1)
+            .returning(|_, _, _, _| Err(anyhow::anyhow!("nope")));
+        destroyer.expect_remove().times(0);
+        let ret = reconcile_inner(destroyer, pod.clone(), data.clone()).await;
+        assert!(ret.is_err());
+
+        let mut destroyer = MockDestroyer::new();
+        destroyer.expect_shutdown().times(0);
+        destroyer
+            .expect_remove()
+            .times(1)
+            .withf(|_, how| *how == LastResort::Evict)
+            .returning(|_, _| Ok(()));
+        let ret = reconcile_inner(destroyer, pod.clone(), data.clone()).await;
+        assert_eq!(ret.unwrap(), ReconcilerAction::await_change());
+        assert_eq!(
+            LAST_RESORT_REMOVALS
+                .with_label_values(&["last-resort", "stuck", "evict"])
+                .get(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn reconcile_tries_shutdown_before_last_resort_budget_runs_out() {
+        let pod = Arc::new(stuck_pod("budget", 0));
+        let mut data = make_data_with(&[last_resort_namespace("evict")]);
+        data.last_resort_attempts = 100;
+        data.last_resort_budget = Duration::ZERO;
+        let data = Arc::new(data);
+        // deciding on the pod doesn't start the budget, only the first attempt does
+        assert!(data.history.mark_decided(&pod_key(&pod)));
+        assert!(data.history.attempts(&pod_key(&pod)).is_none());
+
+        let mut destroyer = MockDestroyer::new();
+        destroyer
+            .expect_shutdown()
+            .times(1)
+            .returning(|_, _, _, _| Err(anyhow::anyhow!("nope")));
+        destroyer.expect_remove().times(0);
+        let ret = reconcile_inner(destroyer, pod.clone(), data.clone()).await;
+        assert!(ret.is_err());
+        assert_eq!(data.history.attempts(&pod_key(&pod)).unwrap().failures, 1);
+
+        let mut destroyer = MockDestroyer::new();
+        destroyer.expect_shutdown().times(0);
+        destroyer
+            .expect_remove()
+            .times(1)
+            .withf(|_, how| *how == LastResort::Evict)
+            .returning(|_, _| Ok(()));
+        let ret = reconcile_inner(destroyer, pod.clone(), data.clone()).await;
+        assert_eq!(ret.unwrap(), ReconcilerAction::await_change());
+    }
+
+    #[tokio::test]
+    async fn reconcile_never_removes_pod_unless_main_container_succeeded_and_opted_in() {
+        for (pod, namespace) in [
+            (stuck_pod("failed-main", 1), last_resort_namespace("delete")),
+            (stuck_pod("not-opted-in", 0), ns("stuck")),
+        ] {
+            let pod = Arc::new(pod);
+            let data = Arc::new(make_data_with(&[namespace]));
+            for _ in 0..2 {
+                let mut destroyer = MockDestroyer::new();
+                destroyer
+                    .expect_shutdown()
+                    .times(1)
+                    .returning(|_, _, _, _| Err(anyhow::anyhow!("nope")));
+                destroyer.expect_remove().times(0);
+                let ret = reconcile_inner(destroyer, pod.clone(), data.clone()).await;
+                assert!(ret.is_err());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn reconcile_follows_termination_policy() {
+        let pod = |exit_code: i32, reason: &str, annotation: Option<&str>| {
+            let mut pod = stuck_pod("terminated", exit_code);
+            pod.status.as_mut().unwrap().container_statuses.as_mut().unwrap()[0]
+                .state
+                .as_mut()
+                .unwrap()
+                .terminated
+                .as_mut()
+                .unwrap()
+                .reason = Some(reason.into());
+            pod.metadata.annotations =
+                annotation.map(|policy| BTreeMap::from([("hahaha.nais.io/termination-policy".into(), policy.into())]));
+            Arc::new(pod)
+        };
+        let cases = [
+            (TerminationPolicy::Any, pod(137, "OOMKilled", None), true),
+            (TerminationPolicy::Success, pod(0, "Completed", None), true),
+            (TerminationPolicy::Success, pod(1, "Error", None), false),
+            (TerminationPolicy::ExceptOomKilled, pod(1, "Error", None), true),
+            (TerminationPolicy::ExceptOomKilled, pod(137, "OOMKilled", None), false),
+            (
+                TerminationPolicy::Any,
+                pod(137, "OOMKilled", Some("except-oom-killed")),
+                false,
+            ),
+            (TerminationPolicy::Success, pod(1, "Error", Some("any")), true),
+            (TerminationPolicy::Success, pod(1, "Error", Some("sometimes")), false),
+        ];
+        for (default, pod, shuts_down) in cases {
+            let mut data = make_data_with(&[ns("stuck")]);
+            data.termination_policy = default;
+            let mut destroyer = MockDestroyer::new();
+            destroyer
+                .expect_shutdown()
+                .times(usize::from(shuts_down))
+                .returning(|_, _, _, _| Ok(Shutdown::default()));
+
+            let ret = reconcile_inner(destroyer, pod, Arc::new(data)).await;
+
+            assert!(ret.is_ok(), "{:?}", ret);
+        }
+    }
+
+    #[tokio::test]
+    async fn reconcile_waits_for_grace_period_after_main_container_finished() {
+        let pod = |finished_ago: i64, annotation: Option<&str>| {
+            let mut pod = stuck_pod("grace", 0);
+            pod.status.as_mut().unwrap().container_statuses.as_mut().unwrap()[0]
+                .state
+                .as_mut()
+                .unwrap()
+                .terminated
+                .as_mut()
+                .unwrap()
+                .finished_at = Some(Time(Utc::now() - chrono::Duration::seconds(finished_ago)));
+            pod.metadata.annotations = annotation
+                .map(|grace_period| BTreeMap::from([("hahaha.nais.io/grace-period".into(), grace_period.into())]));
+            Arc::new(pod)
+        };
+        let cases = [
+            (pod(10, None), false),
+            (pod(60, None), true),
+            (pod(10, Some("0s")), true),
+            (pod(10, Some("1m")), false),
+            (pod(120, Some("1m")), true),
+            (pod(10, Some("later")), false),
+        ];
+        for (pod, shuts_down) in cases {
+            let mut data = make_data_with(&[ns("stuck")]);
+            data.grace_period = Duration::from_secs(30);
+            let mut destroyer = MockDestroyer::new();
+            destroyer
+                .expect_shutdown()
+                .times(usize::from(shuts_down))
+                .returning(|_, _, _, _| Ok(Shutdown::default()));
+
+            let ret = reconcile_inner(destroyer, pod, Arc::new(data)).await.unwrap();
+
+            if !shuts_down {
+                assert_ne!(ret, ReconcilerAction::await_change());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn reconcile_finds_main_containers_without_app_label() {
+        // a plain Job pod with a main container that isn't named after any label
+        let job_pod = |annotations: &[(&str, &str)]| {
+            let mut pod = make_pod("job-abc12".into(), None, vec![running_container("cloudsql-proxy")]);
+            pod.status.as_mut().unwrap().container_statuses.as_mut().unwrap()[0].name = "worker".into();
+            pod.metadata.labels = Some(BTreeMap::from([("job-name".into(), "job".into())]));
+            pod.metadata.annotations = Some(
+                annotations
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect(),
+            );
+            pod.spec = Some(PodSpec {
+                containers: vec![
+                    Container {
+                        name: "worker".into(),
+                        ..Default::default()
+                    },
+                    Container {
+                        name: "cloudsql-proxy".into(),
+                        ..Default::default()
+                    },
+                ],
+                ..Default::default()
+            });
+            Arc::new(pod)
+        };
+        // resolution, pod, whether the sidecar gets shut down, whether main containers are found at all
+        let cases = [
+            (
+                "annotation",
+                job_pod(&[("hahaha.nais.io/main-containers", "worker")]),
+                true,
+                true,
+            ),
+            (
+                "annotation",
+                job_pod(&[("hahaha.nais.io/main-containers", "cloudsql-proxy")]),
+                false,
+                true,
+            ),
+            (
+                "annotation",
+                job_pod(&[("hahaha.nais.io/main-containers", "nope")]),
+                false,
+                false,
+            ),
+            ("annotation", job_pod(&[]), false, false),
+            ("app-label", job_pod(&[]), false, false),
+            (
+                "default-container",
+                job_pod(&[("kubectl.kubernetes.io/default-container", "worker")]),
+                true,
+                true,
+            ),
+            ("first", job_pod(&[]), true, true),
+            ("without-action", job_pod(&[]), true, true),
+            ("annotation,app-label,first", job_pod(&[]), true, true),
+        ];
+        for (resolution, pod, shuts_down, found) in cases {
+            let mut data = make_data();
+            data.main_containers = resolution.parse().unwrap();
+            let mut destroyer = MockDestroyer::new();
+            destroyer
+                .expect_shutdown()
+                .times(usize::from(shuts_down))
+                .withf(|_, _, _, container_name| container_name == "cloudsql-proxy")
+                .returning(|_, _, _, _| Ok(Shutdown::default()));
+
+            let ret = reconcile_inner(destroyer, pod, Arc::new(data)).await;
+
+            assert_eq!(ret.is_ok(), found, "{}: {:?}", resolution, ret);
+        }
+    }
+
+    #[test]
+    fn main_container_resolution_is_parsed() {
+        let resolution: MainContainerResolution = " annotation, first,".parse().unwrap();
+        assert_eq!(
+            resolution,
+            MainContainerResolution(vec![MainContainer::Annotation, MainContainer::First])
+        );
+        assert_eq!(resolution.to_string(), "annotation,first");
+        assert!("".parse::<MainContainerResolution>().is_err());
+        assert!("annotation,label".parse::<MainContainerResolution>().is_err());
+    }
+
+    /// A pod in the `stuck` namespace with two main containers, where `worker-a` exited with code 0
+    /// and `worker-b` is still running without an exit code
+    fn two_workers_pod(name: &str, worker_b_exit: Option<(i32, &str)>) -> Pod {
+        let worker_b = match worker_b_exit {
+            None => running_container("worker-b"),
+            Some((exit_code, reason)) => ContainerStatus {
+                name: "worker-b".into(),
+                state: Some(ContainerState {
+                    terminated: Some(ContainerStateTerminated {
+                        exit_code,
+                        reason: Some(reason.into()),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        };
+        let mut pod = make_pod(
+            "worker-a".into(),
+            None,
+            vec![worker_b, running_container("cloudsql-proxy")],
+        );
+        pod.metadata.name = Some(name.into());
+        pod.metadata.namespace = Some("stuck".into());
+        pod.metadata.labels = Some(BTreeMap::from([("app".into(), name.into())]));
+        pod.metadata.annotations = Some(BTreeMap::from([(
+            "hahaha.nais.io/main-containers".into(),
+            "worker-a, worker-b".into(),
+        )]));
+        pod
+    }
+
+    #[tokio::test]
+    async fn reconcile_waits_for_all_main_containers() {
+        let cases = [
+            (TerminationPolicy::Any, None, false),
+            (TerminationPolicy::Any, Some((0, "Completed")), true),
+            (TerminationPolicy::Success, Some((1, "Error")), false),
+            (TerminationPolicy::ExceptOomKilled, Some((1, "Error")), true),
+            (TerminationPolicy::ExceptOomKilled, Some((137, "OOMKilled")), false),
+        ];
+        for (policy, worker_b_exit, shuts_down) in cases {
+            let mut data = make_data_with(&[ns("stuck")]);
+            data.termination_policy = policy;
+            let mut destroyer = MockDestroyer::new();
+            destroyer
+                .expect_shutdown()
+                .times(usize::from(shuts_down))
+                .withf(|_, _, _, container_name| container_name == "cloudsql-proxy")
+                .returning(|_, _, _, _| Ok(Shutdown::default()));
+
+            let pod = Arc::new(two_workers_pod("workers", worker_b_exit));
+            let ret = reconcile_inner(destroyer, pod, Arc::new(data)).await;
+
+            assert!(ret.is_ok(), "{:?}", ret);
+        }
+    }
+
+    #[tokio::test]
+    async fn reconcile_only_removes_pod_as_last_resort_when_all_main_containers_succeeded() {
+        for (worker_b_exit, removes) in [((1, "Error"), false), ((0, "Completed"), true)] {
+            let pod = Arc::new(two_workers_pod("workers-last-resort", Some(worker_b_exit)));
+            let data = Arc::new(make_data_with(&[last_resort_namespace("delete")]));
+            let mut destroyer = MockDestroyer::new();
+            destroyer
+                .expect_shutdown()
+                .times(1)
+                .returning(|_, _, _, _| Err(anyhow::anyhow!("nope")));
+            destroyer.expect_remove().times(0);
+            let ret = reconcile_inner(destroyer, pod.clone(), data.clone()).await;
+            assert!(ret.is_err());
+
+            let mut destroyer = MockDestroyer::new();
+            destroyer
+                .expect_shutdown()
+                .times(usize::from(!removes))
+                .returning(|_, _, _, _| Err(anyhow::anyhow!("nope")));
+            destroyer
+                .expect_remove()
+                .times(usize::from(removes))
+                .returning(|_, _| Ok(()));
+            let ret = reconcile_inner(destroyer, pod.clone(), data.clone()).await;
+            assert_eq!(ret.is_ok(), removes, "{:?}", ret);
+        }
+    }
+
+    #[tokio::test]
+    async fn reconcile_leaves_native_sidecars_alone_and_reports_them_once() {
+        let pod = |name: &str, legacy_sidecar: bool| {
+            let labels = BTreeMap::from([("app".into(), name.into())]);
+            let extra_containers = if legacy_sidecar {
+                vec![running_container("cloudsql-proxy")]
+            } else {
+                vec![]
+            };
+            let mut pod = make_pod(name.into(), Some(labels), extra_containers);
+            pod.metadata.namespace = Some("native".into());
+            pod.spec = Some(PodSpec {
+                init_containers: Some(vec![Container {
+                    name: "linkerd-proxy".into(),
+                    restart_policy: Some("Always".into()),
+                    ..Default::default()
+                }]),
+                containers: std::iter::once(name)
+                    .chain(legacy_sidecar.then_some("cloudsql-proxy"))
+                    .map(|name| Container {
+                        name: name.into(),
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            });
+            pod.status.as_mut().unwrap().init_container_statuses = Some(vec![running_container("linkerd-proxy")]);
+            Arc::new(pod)
+        };
+        let data = Arc::new(make_data_with(&[ns("native")]));
+
+        // the migrated pod is looked at twice, but only reported once
+        let migrated = pod("migrated", false);
+        for (pod, legacy_sidecar) in [(migrated.clone(), false), (migrated, false), (pod("mixed", true), true)] {
+            let mut destroyer = MockDestroyer::new();
+            destroyer
+                .expect_shutdown()
+                .times(usize::from(legacy_sidecar))
+                .withf(|_, _, _, container_name| container_name == "cloudsql-proxy")
+                .returning(|_, _, _, _| Ok(Shutdown::default()));
+            let ret = reconcile_inner(destroyer, pod, data.clone()).await;
+            assert!(ret.is_ok(), "{:?}", ret);
+        }
+
+        assert_eq!(MIGRATED_PODS.with_label_values(&["migrated", "native"]).get(), 1);
+        assert_eq!(MIGRATED_PODS.with_label_values(&["mixed", "native"]).get(), 0);
+        assert_eq!(MIXED_SIDECAR_PODS.with_label_values(&["mixed", "native"]).get(), 1);
+    }
+
+    #[tokio::test]
+    async fn reconcile_only_plans_shutdowns_in_dry_run() {
+        let namespace = |name: &str, dry_run: Option<&str>| Namespace {
+            metadata: ObjectMeta {
+                name: Some(name.into()),
+                annotations: dry_run.map(|value| BTreeMap::from([("hahaha.nais.io/dry-run".into(), value.into())])),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let pod = |namespace: &str| {
+            let mut pod = stuck_pod("dry-run", 0);
+            pod.metadata.namespace = Some(namespace.into());
+            Arc::new(pod)
+        };
+        let cases = [
+            (true, pod("dry"), false),
+            (false, pod("dry-by-annotation"), false),
+            (true, pod("wet-by-annotation"), true),
+            (true, pod("invalid-annotation"), false),
+        ];
+        for (default, pod, shuts_down) in cases {
+            let mut data = make_data_with(&[
+                namespace("dry", None),
+                namespace("dry-by-annotation", Some("true")),
+                namespace("wet-by-annotation", Some("false")),
+                namespace("invalid-annotation", Some("maybe")),
+            ]);
+            data.dry_run = default;
+            let data = Arc::new(data);
+            // a second look at the pod doesn't plan the shutdown again
+            for _ in 0..2 {
+                let mut destroyer = MockDestroyer::new();
+                destroyer
+                    .expect_shutdown()
+                    .times(usize::from(shuts_down))
+                    .returning(|_, _, _, _| Ok(Shutdown::default()));
+                destroyer.expect_remove().times(0);
+                let ret = reconcile_inner(destroyer, pod.clone(), data.clone()).await;
+                assert!(ret.is_ok(), "{:?}", ret);
+                if shuts_down {
+                    break;
+                }
+            }
+
+            let namespace = pod.metadata.namespace.as_deref().unwrap();
+            let planned = DRY_RUN_PLANNED_SHUTDOWNS
+                .with_label_values(&["cloudsql-proxy", "dry-run", namespace, "portforward"])
+                .get();
+            assert_eq!(planned, u64::from(!shuts_down), "{}", namespace);
+        }
+
+        // the annotation can't be seen before the namespace is known, so nothing is done until then
+        let data = Arc::new(make_data());
+        let mut destroyer = MockDestroyer::new();
+        destroyer.expect_shutdown().times(0);
+        let ret = reconcile_inner(destroyer, pod("dry-but-unknown"), data).await;
+        assert_eq!(ret.unwrap(), ReconcilerAction::requeue(NAMESPACE_WAIT));
+    }
+
+    #[tokio::test]
+    async fn reconcile_skips_pods_in_filtered_namespaces() {
+        let namespace = |name: &str, team: &str| Namespace {
+            metadata: ObjectMeta {
+                name: Some(name.into()),
+                labels: Some(BTreeMap::from([("team".into(), team.into())])),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let pod = |namespace: &str| {
+            let mut pod = stuck_pod("filtered", 0);
+            pod.metadata.namespace = Some(namespace.into());
+            Arc::new(pod)
+        };
+        let mut data = make_data_with(&[
+            namespace("team-a", "a"),
+            namespace("team-b", "b"),
+            namespace("kube-system", "a"),
+        ]);
+        data.namespace_filter = NamespaceFilter {
+            include_selector: Some("team=a".parse().unwrap()),
+            exclude: vec!["kube-system".into()],
+            ..Default::default()
+        };
+        let data = Arc::new(data);
+
+        for (pod, shuts_down) in [
+            (pod("team-a"), true),
+            (pod("team-b"), false),
+            (pod("team-b"), false),
+            (pod("kube-system"), false),
+        ] {
+            let mut destroyer = MockDestroyer::new();
+            destroyer
+                .expect_shutdown()
+                .times(usize::from(shuts_down))
+                .returning(|_, _, _, _| Ok(Shutdown::default()));
+            let ret = reconcile_inner(destroyer, pod, data.clone()).await;
+            assert!(ret.is_ok(), "{:?}", ret);
+        }
+
+        // pods in namespaces that aren't known yet are looked at again later
+        let mut destroyer = MockDestroyer::new();
+        destroyer.expect_shutdown().times(0);
+        let ret = reconcile_inner(destroyer, pod("team-c"), data.clone()).await;
+        assert_ne!(ret.unwrap(), ReconcilerAction::await_change());
+
+        assert_eq!(SKIPPED_PODS.with_label_values(&["team-b", "namespace"]).get(), 1);
+        assert_eq!(SKIPPED_PODS.with_label_values(&["kube-system", "namespace"]).get(), 1);
+    }
+
+    #[tokio::test]
+    async fn reconcile_skips_pods_and_containers_with_skip_annotation() {
+        let annotated = |name: &str, namespace: &str, annotations: &[(&str, &str)]| {
+            let mut pod = stuck_pod(name, 0);
+            pod.metadata.namespace = Some(namespace.into());
+            pod.metadata.annotations = Some(
+                annotations
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            );
+            pod.status
+                .as_mut()
+                .unwrap()
+                .container_statuses
+                .as_mut()
+                .unwrap()
+                .push(running_container("istio-proxy"));
+            Arc::new(pod)
+        };
+        let quiet = Namespace {
+            metadata: ObjectMeta {
+                name: Some("quiet".into()),
+                annotations: Some(BTreeMap::from([("hahaha.nais.io/skip".into(), "true".into())])),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let data = Arc::new(make_data_with(&[ns("loud"), quiet]));
+
+        for (pod, shut_down) in [
+            (
+                annotated("skip-pod", "loud", &[("hahaha.nais.io/skip", "true")]),
+                vec![],
+            ),
+            (annotated("skip-namespace", "quiet", &[]), vec![]),
+            (
+                annotated("opt-back-in", "quiet", &[("hahaha.nais.io/skip", "false")]),
+                vec!["cloudsql-proxy", "istio-proxy"],
+            ),
+            (
+                annotated("invalid", "loud", &[("hahaha.nais.io/skip", "yes")]),
+                vec!["cloudsql-proxy", "istio-proxy"],
+            ),
+            (
+                annotated(
+                    "skip-container",
+                    "loud",
+                    &[("hahaha.nais.io/skip-containers", "istio-proxy")],
+                ),
+                vec!["cloudsql-proxy"],
+            ),
+            (
+                annotated(
+                    "skip-containers",
+                    "loud",
+                    &[("hahaha.nais.io/skip-containers", "cloudsql-proxy, istio-proxy")],
+                ),
+                vec![],
+            ),
+        ] {
+            let mut destroyer = MockDestroyer::new();
+            let expected = shut_down.clone();
+            destroyer
+                .expect_shutdown()
+                .times(shut_down.len())
+                .withf(move |_, _, _, container_name| expected.contains(&container_name))
+                .returning(|_, _, _, _| Ok(Shutdown::default()));
+            let ret = reconcile_inner(destroyer, pod, data.clone()).await;
+            assert!(ret.is_ok(), "{:?}", ret);
+        }
+
+        // skipped pods are only counted once, however often they're looked at
+        let mut destroyer = MockDestroyer::new();
+        destroyer.expect_shutdown().times(0);
+        let ret = reconcile_inner(destroyer, annotated("skip-namespace", "quiet", &[]), data.clone()).await;
+        assert_eq!(ret.unwrap(), ReconcilerAction::await_change());
+        assert_eq!(SKIPPED_PODS.with_label_values(&["loud", "annotation"]).get(), 1);
+        assert_eq!(SKIPPED_PODS.with_label_values(&["quiet", "annotation"]).get(), 1);
+
+        // a namespace that may want its pods skipped has to be known before they're acted on
+        let mut destroyer = MockDestroyer::new();
+        destroyer.expect_shutdown().times(0);
+        let ret = reconcile_inner(
+            destroyer,
+            annotated("skip-unknown", "quiet", &[]),
+            Arc::new(make_data()),
+        )
+        .await;
+        assert_eq!(ret.unwrap(), ReconcilerAction::requeue(NAMESPACE_WAIT));
+    }
+
+    #[tokio::test]
+    async fn reconcile_uses_profile_of_watch_the_pod_came_from() {
+        // an Argo workflow pod, whose main container is the default container and whose `wait` sidecar has no built-in action
+        let argo_pod = |name: &str| {
+            let mut pod = make_pod(name.into(), None, vec![running_container("wait")]);
+            pod.metadata.namespace = Some("workflows".into());
+            pod.metadata.annotations = Some(BTreeMap::from([(
+                "kubectl.kubernetes.io/default-container".into(),
+                name.into(),
+            )]));
+            pod
+        };
+        let mut argo_pods = Writer::default();
+        argo_pods.apply_watcher_event(&watcher::Event::Applied(argo_pod("step-1")));
+        let argo = Watch {
+            name: "argo".into(),
+            labels: Some("workflows.argoproj.io/workflow".into()),
+            main_containers: Some("default-container".parse().unwrap()),
+            actions: ActionTable {
+                actions: BTreeMap::from([(
+                    "wait".into(),
+                    vec![Action::Exec {
+                        command: vec!["/bin/kill".into(), "1".into()],
+                        timeout: None,
+                        retry: None,
+                    }],
+                )]),
+                rules: vec![],
+            },
+            ..Default::default()
+        };
+        let mut data = make_data_with(&[ns("workflows")]);
+        data.watches = vec![
+            (
+                Watch::from_selector("nais.io/naisjob=true".into()),
+                Writer::default().as_reader(),
+            ),
+            (argo, argo_pods.as_reader()),
+        ];
+        let data = Arc::new(data);
+
+        let mut destroyer = MockDestroyer::new();
+        destroyer
+            .expect_shutdown()
+            .times(1)
+            .withf(|actions, _, _, container_name| {
+                container_name == "wait"
+                    && matches!(actions, [Action::Exec { command, .. }] if command[0] == "/bin/kill")
+            })
+            .returning(|_, _, _, _| Ok(Shutdown::default()));
+        let ret = reconcile_inner(destroyer, Arc::new(argo_pod("step-1")), data.clone()).await;
+        assert!(ret.is_ok(), "{:?}", ret);
+
+        // the same kind of pod from another watch has neither the main containers nor the actions of the profile
+        let mut destroyer = MockDestroyer::new();
+        destroyer.expect_shutdown().times(0);
+        let ret = reconcile_inner(destroyer, Arc::new(argo_pod("step-2")), data.clone()).await;
+        assert!(ret.is_err());
+    }
+
+    #[tokio::test]
+    async fn reconcile_only_acts_as_leader() {
+        let (leader_sender, leader) = watch::channel(false);
+        let mut data = make_data_with(&[ns("stuck")]);
+        data.leader = leader;
+        let data = Arc::new(data);
+
+        let mut destroyer = MockDestroyer::new();
+        destroyer.expect_shutdown().times(0);
+        let ret = reconcile_inner(destroyer, Arc::new(stuck_pod("standby", 0)), data.clone()).await;
+        assert_eq!(ret.unwrap(), ReconcilerAction::await_change());
+
+        leader_sender.send_replace(true);
+        let mut destroyer = MockDestroyer::new();
+        destroyer
+            .expect_shutdown()
+            .times(1)
+            .returning(|_, _, _, _| Ok(Shutdown::default()));
+        let ret = reconcile_inner(destroyer, Arc::new(stuck_pod("standby", 0)), data.clone()).await;
+        assert!(ret.is_ok(), "{:?}", ret);
+    }
+
+    /// shuts down sidecars forever, e.g. retrying a slow action, while another task takes leadership away
+    struct SlowDestroyer;
+
+    #[async_trait::async_trait]
+    impl Destroyer for SlowDestroyer {
+        async fn shutdown(&self, _: &[Action], _: u32, _: &str, _: &str) -> anyhow::Result<Shutdown> {
+            std::future::pending().await
+        }
+
+        async fn remove(&self, _: &str, _: LastResort) -> anyhow::Result<()> {
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn reconcile_stops_acting_when_leadership_is_lost() {
+        let (leader_sender, leader) = watch::channel(true);
+        let mut data = make_data_with(&[ns("stuck")]);
+        data.leader = leader;
+        let data = Arc::new(data);
+        let pod = Arc::new(stuck_pod("deposed", 0));
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            leader_sender.send_replace(false);
+        });
+        let ret = tokio::time::timeout(
+            Duration::from_secs(5),
+            reconcile_inner(SlowDestroyer, pod.clone(), data.clone()),
+        )
+        .await
+        .expect("the reconcile should stop when leadership is lost");
+        assert_eq!(ret.unwrap(), ReconcilerAction::await_change());
+        assert!(data.history.sidecar(&pod_key(&pod), "cloudsql-proxy").is_none());
+    }
+
+    #[tokio::test]
+    async fn reconcile_looks_again_for_actions_of_unsupported_sidecars() {
+        let mut pod = stuck_pod("unsupported", 0);
+        pod.metadata.namespace = Some("default".into());
+        pod.status.as_mut().unwrap().container_statuses.as_mut().unwrap()[1].name = "mystery".into();
+        let pod = Arc::new(pod);
+        let data = Arc::new(make_data());
+
+        // the sidecar is only reported once, however often the pod is looked at
+        for _ in 0..2 {
+            let mut destroyer = MockDestroyer::new();
+            destroyer.expect_shutdown().times(0);
+            let ret = reconcile_inner(destroyer, pod.clone(), data.clone()).await;
+            assert_eq!(ret.unwrap(), ReconcilerAction::requeue(UNSUPPORTED_RECHECK));
+        }
+        assert_eq!(
+            UNSUPPORTED_SIDECARS
+                .with_label_values(&["mystery", "unsupported", "default"])
+                .get(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn reconcile_applies_default_http_transport() {
+        let mut destroyer = MockDestroyer::new();
+        destroyer
+            .expect_shutdown()
+            .times(1)
+            .withf(|actions, _, _, _| {
+                matches!(
+                    actions,
+                    [Action::Portforward(HttpRequest {
+                        transport: Some(Transport::PodIp),
+                        ..
+                    })]
+                )
+            })
+            .returning(|_, _, _, _| Ok(Shutdown::default()));
+
+        let name: String = String::from("pod-ip");
+        let labels: BTreeMap<String, String> = BTreeMap::from([("app".into(), name.clone())]);
+        let pod = make_pod(name, Some(labels), vec![running_container("cloudsql-proxy")]);
+        let mut data = make_data();
+        data.http_transport = Transport::PodIp;
+
+        let ret = reconcile_inner(destroyer, Arc::new(pod), Arc::new(data)).await;
+
+        assert!(ret.is_ok());
+    }
+
+    #[tokio::test]
+    async fn error_policy_backs_off_with_failures() {
+        let pod = Arc::new(stuck_pod("backoff", 0));
+        let data = Arc::new(make_data_with(&[ns("stuck")]));
+        let delay = error_backoff(&data.history, &pod);
+        assert!(delay <= Duration::from_secs(5), "without failures: {:?}", delay);
+
+        let mut failures = 0;
+        for expected in [5, 10, 20, 40] {
+            let mut destroyer = MockDestroyer::new();
+            destroyer
+                .expect_shutdown()
+                .times(1)
+                .returning(|_, _, _, _| Err(anyhow::anyhow!("nope")));
+            let ret = reconcile_inner(destroyer, pod.clone(), data.clone()).await;
+            assert!(ret.is_err());
+            failures += 1;
+
+            let expected = Duration::from_secs(expected);
+            let delay = error_backoff(&data.history, &pod);
+            assert!(
+                delay <= expected && delay >= expected / 2,
+                "after {} failures: {:?}",
+                failures,
+                delay
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn reconcile_falls_back_on_invalid_action_annotation() {
+        let mut destroyer = MockDestroyer::new();
+        destroyer
+            .expect_shutdown()
+            .times(1)
+            .withf(|actions, _, _, _| matches!(actions, [Action::Portforward(HttpRequest { port: 9091, .. })]))
+            .returning(|_, _, _, _| Ok(Shutdown::default()));
+
+        let name: String = String::from("oh-no");
+        let labels: BTreeMap<String, String> = BTreeMap::from([("app".into(), name.clone())]);
+        let mut pod = make_pod(name, Some(labels), vec![running_container("cloudsql-proxy")]);
+        pod.metadata.annotations = Some(BTreeMap::from([(
+            "hahaha.nais.io/action.cloudsql-proxy".into(),
+            "portforward:POST:/quit".into(),
+        )]));
+        let pod = Arc::new(pod);
+        let data = Arc::new(make_data());
+
+        let ret = reconcile_inner(destroyer, pod.clone(), data.clone()).await;
+
+        assert!(ret.is_ok());
+        // the invalid annotation has been reported, and isn't reported again
+        assert!(!data.history.mark_invalid_annotation(&pod_key(&pod), "cloudsql-proxy"));
+    }
+
     #[tokio::test]
     async fn reconcile_err_on_misconfigured_pod() {
         let mut destroyer = MockDestroyer::new();
-        destroyer.expect_shutdown().times(0).returning(|_, _, _| Ok(()));
+        destroyer
+            .expect_shutdown()
+            .times(0)
+            .returning(|_, _, _, _| Ok(Shutdown::default()));
 
         let name: String = String::from("oh-no");
 
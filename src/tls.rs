@@ -0,0 +1,83 @@
+use crate::actions::Tls;
+use anyhow::{anyhow, Context, Result};
+use lazy_static::lazy_static;
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, ClientConfig, RootCertStore, ServerName,
+};
+use std::{convert::TryFrom, fs::File, io::BufReader, sync::Arc, time::SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+use tracing::warn;
+
+lazy_static! {
+    /// The CA certificates of the system, used when an action doesn't have its own
+    static ref NATIVE_ROOTS: RootCertStore = {
+        let mut roots = RootCertStore::empty();
+        match rustls_native_certs::load_native_certs() {
+            Ok(certs) => {
+                let certs: Vec<Vec<u8>> = certs.into_iter().map(|c| c.0).collect();
+                roots.add_parsable_certificates(&certs);
+            }
+            Err(e) => warn!("could not load system CA certificates: {e}"),
+        }
+        roots
+    };
+}
+
+/// Performs a TLS handshake over an established connection to `host`
+///
+/// The CA file is read on every call, so that rotated certificates in a mounted secret are picked up.
+pub async fn connect<S>(tls: &Tls, host: &str, stream: S) -> Result<TlsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let name = tls.server_name.as_deref().unwrap_or(host);
+    let server_name = ServerName::try_from(name).with_context(|| format!("invalid server name `{name}`"))?;
+    let connector = TlsConnector::from(Arc::new(client_config(tls)?));
+    connector
+        .connect(server_name, stream)
+        .await
+        .with_context(|| format!("TLS handshake with `{name}` failed"))
+}
+
+fn client_config(tls: &Tls) -> Result<ClientConfig> {
+    let builder = ClientConfig::builder().with_safe_defaults();
+    if tls.insecure_skip_verify {
+        return Ok(builder
+            .with_custom_certificate_verifier(Arc::new(SkipVerify))
+            .with_no_client_auth());
+    }
+    let roots = match &tls.ca_file {
+        None => NATIVE_ROOTS.clone(),
+        Some(path) => {
+            let file = File::open(path).with_context(|| format!("could not read CA file {}", path.display()))?;
+            let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+                .with_context(|| format!("invalid CA file {}", path.display()))?;
+            let mut roots = RootCertStore::empty();
+            let (added, _) = roots.add_parsable_certificates(&certs);
+            if added == 0 {
+                return Err(anyhow!("no CA certificates found in {}", path.display()));
+            }
+            roots
+        }
+    };
+    Ok(builder.with_root_certificates(roots).with_no_client_auth())
+}
+
+/// Accepts any server certificate
+struct SkipVerify;
+
+impl ServerCertVerifier for SkipVerify {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
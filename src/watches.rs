@@ -0,0 +1,333 @@
+use anyhow::{anyhow, Context, Result};
+use futures::{stream, Stream, StreamExt};
+use k8s_openapi::api::core::v1::Pod;
+use kube::{
+    runtime::{
+        reflector::{self, store::Writer, ObjectRef, Store},
+        watcher, WatchStreamExt,
+    },
+    Api,
+};
+use serde::Deserialize;
+use std::{collections::BTreeMap, fmt, path::Path};
+
+use crate::{
+    actions::{self, ActionTable},
+    namespaces::LabelSelector,
+    pod::MainContainerResolution,
+};
+
+/// A selection of Pods to watch, with an optional profile for handling them
+#[derive(Clone, Debug, Default)]
+pub struct Watch {
+    /// Name of the watch, for logging
+    pub name: String,
+    /// Label selector for the Pods
+    pub labels: Option<String>,
+    /// Field selector for the Pods
+    pub fields: Option<String>,
+    /// How to find the main containers of the Pods, instead of the default
+    pub main_containers: Option<MainContainerResolution>,
+    /// Shutdown actions for sidecars in the Pods, which win over the action file
+    pub actions: ActionTable,
+}
+
+impl Watch {
+    /// The watch used when there's no watch file, which selects Pods by labels alone
+    pub fn from_selector(labels: String) -> Self {
+        Self {
+            name: "default".into(),
+            labels: Some(labels),
+            ..Default::default()
+        }
+    }
+
+    /// Configure a watcher for the Pods, with `fields` added to the field selector of the watch
+    fn config(&self, fields: Option<&str>) -> watcher::Config {
+        let mut config = watcher::Config::default();
+        if let Some(labels) = &self.labels {
+            config = config.labels(labels);
+        }
+        let fields: Vec<&str> = self.fields.as_deref().into_iter().chain(fields).collect();
+        if !fields.is_empty() {
+            config = config.fields(&fields.join(","));
+        }
+        config
+    }
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "watch {}", self.name)?;
+        if let Some(labels) = &self.labels {
+            write!(f, " with labels `{labels}`")?;
+        }
+        if let Some(fields) = &self.fields {
+            write!(f, " with fields `{fields}`")?;
+        }
+        Ok(())
+    }
+}
+
+/// Load the watches from a watch file
+///
+/// The file is YAML (or JSON) with a top level `watches` list. Each watch has a `name`, `labels` and `fields`
+/// selectors, and optionally a `mainContainerResolution` and `actions` and `rules` like in the action file.
+pub fn load(path: &Path) -> Result<Vec<Watch>> {
+    let contents =
+        std::fs::read_to_string(path).with_context(|| format!("could not read watch file {}", path.display()))?;
+    parse(&contents).with_context(|| format!("invalid watch file {}", path.display()))
+}
+
+/// Parse and validate the contents of a watch file
+fn parse(contents: &str) -> Result<Vec<Watch>> {
+    let file: WatchFile = serde_yaml::from_str(contents)?;
+    if file.watches.is_empty() {
+        return Err(anyhow!("no watches"));
+    }
+    let mut watches: Vec<Watch> = Vec::new();
+    for entry in file.watches {
+        let name = entry.name.clone();
+        if watches.iter().any(|w| w.name == name) {
+            return Err(anyhow!("duplicate watch `{name}`"));
+        }
+        watches.push(parse_entry(entry).with_context(|| format!("invalid watch `{name}`"))?);
+    }
+    Ok(watches)
+}
+
+/// Parse and validate a single watch from a watch file
+fn parse_entry(entry: WatchEntry) -> Result<Watch> {
+    if let Some(labels) = &entry.labels {
+        labels.parse::<LabelSelector>().context("invalid labels")?;
+    }
+    if let Some(fields) = &entry.fields {
+        check_fields(fields).context("invalid fields")?;
+    }
+    let main_containers = entry
+        .main_container_resolution
+        .map(|resolution| resolution.parse())
+        .transpose()
+        .context("invalid mainContainerResolution")?;
+    Ok(Watch {
+        name: entry.name,
+        labels: entry.labels,
+        fields: entry.fields,
+        main_containers,
+        actions: actions::parse_table(entry.actions, entry.rules)?,
+    })
+}
+
+/// Check that a field selector is made of `key=value`, `key==value` or `key!=value` requirements separated by `,`
+///
+/// The API server only tells which fields it supports when the watch starts, but malformed selectors are caught here.
+fn check_fields(fields: &str) -> Result<()> {
+    let requirements: Vec<&str> = fields.split(',').map(str::trim).filter(|r| !r.is_empty()).collect();
+    if requirements.is_empty() {
+        return Err(anyhow!("field selector must not be empty"));
+    }
+    for requirement in requirements {
+        let key = ["!=", "==", "="]
+            .iter()
+            .find_map(|op| requirement.split_once(op))
+            .map(|(key, _)| key.trim());
+        if key.is_none_or(|key| key.is_empty() || key.contains(char::is_whitespace)) {
+            return Err(anyhow!("invalid field selector requirement `{requirement}`"));
+        }
+    }
+    Ok(())
+}
+
+/// The on-disk format of a watch file, before the individual watches are parsed
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WatchFile {
+    watches: Vec<WatchEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct WatchEntry {
+    name: String,
+    #[serde(default)]
+    labels: Option<String>,
+    #[serde(default)]
+    fields: Option<String>,
+    #[serde(default)]
+    main_container_resolution: Option<String>,
+    #[serde(default)]
+    actions: BTreeMap<String, serde_json::Value>,
+    #[serde(default)]
+    rules: Vec<serde_json::Value>,
+}
+
+/// Watch the Pods of all `watches` as one stream, for a single `Controller`
+///
+/// `fields` is added to the field selector of every watch.
+/// Returns a `Store` with the Pods of all watches, and a `Store` per watch to tell which watches a Pod belongs to.
+pub fn watch_pods(
+    api: Api<Pod>,
+    watches: &[Watch],
+    fields: Option<&str>,
+) -> (
+    Store<Pod>,
+    Vec<Store<Pod>>,
+    impl Stream<Item = Result<Pod, watcher::Error>> + Send + 'static,
+) {
+    let (stores, streams): (Vec<_>, Vec<_>) = watches
+        .iter()
+        .map(|watch| {
+            let (store, writer) = reflector::store();
+            let stream = watcher(api.clone(), watch.config(fields))
+                .default_backoff()
+                .reflect(writer)
+                .boxed();
+            (store, stream)
+        })
+        .unzip();
+    let (merged, mut writer) = reflector::store();
+    let reader = merged.clone();
+    let watch_stores = stores.clone();
+    let pods = stream::select_all(streams)
+        .map(move |event| match event {
+            Ok(event) => merge(event, &stores, &mut writer, &merged)
+                .into_iter()
+                .map(Ok)
+                .collect(),
+            Err(err) => vec![Err(err)],
+        })
+        .flat_map(stream::iter);
+    (reader, watch_stores, pods)
+}
+
+/// Apply an event from one of the watches to the `Store` with the Pods of all watches, returning the touched Pods
+///
+/// The `stores` of the watches must already have seen the event.
+/// A Pod is only removed once no watch has it anymore.
+fn merge(event: watcher::Event<Pod>, stores: &[Store<Pod>], writer: &mut Writer<Pod>, merged: &Store<Pod>) -> Vec<Pod> {
+    let watched = |pod: &Pod| {
+        let key = ObjectRef::from_obj(pod);
+        stores.iter().any(|store| store.get(&key).is_some())
+    };
+    match event {
+        watcher::Event::Applied(pod) => {
+            writer.apply_watcher_event(&watcher::Event::Applied(pod.clone()));
+            vec![pod]
+        }
+        watcher::Event::Deleted(pod) => {
+            if !watched(&pod) {
+                writer.apply_watcher_event(&watcher::Event::Deleted(pod.clone()));
+            }
+            vec![pod]
+        }
+        watcher::Event::Restarted(pods) => {
+            for gone in merged.state().into_iter().filter(|pod| !watched(pod)) {
+                writer.apply_watcher_event(&watcher::Event::Deleted((*gone).clone()));
+            }
+            for pod in &pods {
+                writer.apply_watcher_event(&watcher::Event::Applied(pod.clone()));
+            }
+            pods
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pod::MainContainer;
+    use kube::api::ObjectMeta;
+
+    fn pod(name: &str) -> Pod {
+        Pod {
+            metadata: ObjectMeta {
+                name: Some(name.into()),
+                namespace: Some("default".into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn watches_are_parsed() {
+        let watches = parse(
+            r#"
+watches:
+  - name: naisjobs
+    labels: nais.io/naisjob=true
+  - name: argo
+    labels: workflows.argoproj.io/workflow
+    fields: status.phase=Running,spec.nodeName!=
+    mainContainerResolution: default-container,first
+    actions:
+      wait:
+        - exec:
+            command: ["/bin/kill", "1"]
+"#,
+        )
+        .unwrap();
+        assert_eq!(watches.len(), 2);
+        assert_eq!(
+            watches[0].to_string(),
+            "watch naisjobs with labels `nais.io/naisjob=true`"
+        );
+        assert!(watches[0].main_containers.is_none());
+        assert!(watches[0].actions.actions.is_empty());
+        assert_eq!(
+            watches[1].main_containers.as_ref().unwrap().0,
+            vec![MainContainer::DefaultContainer, MainContainer::First]
+        );
+        assert!(watches[1].actions.actions.contains_key("wait"));
+
+        for (contents, expected) in [
+            ("watches: []", "no watches"),
+            ("watches:\n  - name: a\n  - name: a", "duplicate watch `a`"),
+            ("watches:\n  - name: a\n    labels: \"=x\"", "invalid labels"),
+            (
+                "watches:\n  - name: a\n    mainContainerResolution: nope",
+                "invalid mainContainerResolution",
+            ),
+            ("watches:\n  - name: a\n    selector: x", "unknown field `selector`"),
+            ("watches:\n  - name: a\n    fields: status.phase", "invalid fields"),
+            ("watches:\n  - name: a\n    fields: \"=Running\"", "invalid fields"),
+            ("watches:\n  - name: a\n    fields: \" , \"", "must not be empty"),
+        ] {
+            let err = format!("{:#}", parse(contents).unwrap_err());
+            assert!(err.contains(expected), "expected `{}` in `{}`", expected, err);
+        }
+    }
+
+    #[test]
+    fn pods_are_kept_while_any_watch_has_them() {
+        let (a, mut a_writer) = reflector::store();
+        let (b, mut b_writer) = reflector::store();
+        let stores = [a, b];
+        let (merged, mut writer) = reflector::store::<Pod>();
+        let names = |store: &Store<Pod>| {
+            let mut names: Vec<String> = store.state().iter().map(|p| p.metadata.name.clone().unwrap()).collect();
+            names.sort();
+            names
+        };
+
+        // both watches see `shared`, only `a` sees `only-a`
+        let event = watcher::Event::Restarted(vec![pod("shared"), pod("only-a")]);
+        a_writer.apply_watcher_event(&event);
+        assert_eq!(merge(event, &stores, &mut writer, &merged).len(), 2);
+        let event = watcher::Event::Applied(pod("shared"));
+        b_writer.apply_watcher_event(&event);
+        merge(event, &stores, &mut writer, &merged);
+        assert_eq!(names(&merged), ["only-a", "shared"]);
+
+        // `a` loses both Pods in a restart, but `b` still has `shared`
+        let event = watcher::Event::Restarted(vec![]);
+        a_writer.apply_watcher_event(&event);
+        merge(event, &stores, &mut writer, &merged);
+        assert_eq!(names(&merged), ["shared"]);
+
+        let event = watcher::Event::Deleted(pod("shared"));
+        b_writer.apply_watcher_event(&event);
+        merge(event, &stores, &mut writer, &merged);
+        assert!(names(&merged).is_empty());
+    }
+}